@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    // checks the prefix fits the address and masks off the host bits. anything that
+    // didn't come through here (like a rule sent over ipc) should go through it first
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        let addr = addr.to_canonical();
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > bits {
+            return Err(format!("bad prefix length {}", prefix));
+        }
+
+        Ok(Cidr {
+            addr: masked(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // dual-stack sockets hand us ipv4 peers as v4-mapped ipv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(ip) as u128,
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix: u8, bits: u32) -> bool {
+    match bits.checked_sub(prefix as u32) {
+        Some(shift) => net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0),
+        None => false,
+    }
+}
+
+// zeroes the host bits, so 10.0.0.5/8 is the same rule as 10.0.0.0/8
+fn masked(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        }
+    }
+}
+
+// "10.0.0.0/8", "fd00::/8", or a plain address for just that one
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("bad address {}: {}", addr, e))?
+            .to_canonical();
+
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .map_err(|_| format!("bad prefix length {}", prefix))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AclKind {
+    Allow,
+    Deny,
+}
+
+impl FromStr for AclKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AclKind, String> {
+        match s {
+            "allow" => Ok(AclKind::Allow),
+            "deny" => Ok(AclKind::Deny),
+            _ => Err(format!("expected allow or deny, got {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Acl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Acl {
+    // deny always wins. an empty allow list lets in everyone who isn't denied
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|c| c.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip))
+    }
+
+    fn list_mut(&mut self, kind: AclKind) -> &mut Vec<Cidr> {
+        match kind {
+            AclKind::Allow => &mut self.allow,
+            AclKind::Deny => &mut self.deny,
+        }
+    }
+
+    pub fn add(&mut self, kind: AclKind, cidr: Cidr) {
+        let list = self.list_mut(kind);
+        if !list.contains(&cidr) {
+            list.push(cidr);
+        }
+    }
+
+    pub fn remove(&mut self, kind: AclKind, cidr: Cidr) -> bool {
+        let list = self.list_mut(kind);
+        let len = list.len();
+        list.retain(|c| *c != cidr);
+        list.len() != len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_masks_host_bits() {
+        assert_eq!(cidr("10.0.0.5/8"), cidr("10.0.0.0/8"));
+        assert_eq!(cidr("10.0.0.5/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("fd00::1/8").to_string(), "fd00::/8");
+        assert_eq!(cidr("1.2.3.4/0").to_string(), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn parse_plain_address() {
+        assert_eq!(cidr("10.0.0.5").to_string(), "10.0.0.5/32");
+        assert_eq!(cidr("fd00::1").to_string(), "fd00::1/128");
+        assert_eq!(cidr("::ffff:10.0.0.5"), cidr("10.0.0.5/32"));
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("nyah/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn new_checks_like_parse() {
+        assert_eq!(Cidr::new(ip("10.0.0.5"), 8), Ok(cidr("10.0.0.0/8")));
+        assert_eq!(Cidr::new(ip("::ffff:10.0.0.5"), 32), Ok(cidr("10.0.0.5")));
+        assert!(Cidr::new(ip("10.0.0.0"), 33).is_err());
+        assert!(Cidr::new(ip("fd00::"), 129).is_err());
+    }
+
+    #[test]
+    fn unchecked_prefix_matches_nothing() {
+        let bad = Cidr {
+            addr: ip("10.0.0.0"),
+            prefix: 200,
+        };
+        assert!(!bad.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn contains_v4() {
+        let net = cidr("192.168.1.0/24");
+        assert!(net.contains(&ip("192.168.1.1")));
+        assert!(net.contains(&ip("192.168.1.255")));
+        assert!(!net.contains(&ip("192.168.2.1")));
+        assert!(net.contains(&ip("::ffff:192.168.1.7")));
+        assert!(!net.contains(&ip("fd00::1")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let net = cidr("fd00::/8");
+        assert!(net.contains(&ip("fd12:3456::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+        assert!(!net.contains(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn contains_everything_at_zero() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("8.8.8.8")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn contains_one_address_at_full_length() {
+        let one = cidr("10.0.0.5/32");
+        assert!(one.contains(&ip("10.0.0.5")));
+        assert!(!one.contains(&ip("10.0.0.4")));
+        assert!(!one.contains(&ip("10.0.0.6")));
+
+        let one = cidr("fd00::5/128");
+        assert!(one.contains(&ip("fd00::5")));
+        assert!(!one.contains(&ip("fd00::6")));
+    }
+
+    #[test]
+    fn acl_remove_matches_unmasked_input() {
+        let mut acl = Acl::default();
+        acl.add(AclKind::Deny, cidr("10.0.0.5/8"));
+        assert!(!acl.permits(&ip("10.1.2.3")));
+        assert!(acl.remove(AclKind::Deny, cidr("10.0.0.0/8")));
+        assert!(acl.permits(&ip("10.1.2.3")));
+    }
+}
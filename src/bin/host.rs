@@ -1,49 +1,171 @@
+use argh::FromArgs;
 use laminar::{Config as LaminarConfig, Socket as LaminarSocket};
+use nyah::acl::{AclKind, Cidr};
+use nyah::crypto::Identity;
 use nyah::state::*;
+use nyah::tracker::ANNOUNCE_INTERVAL;
 use nyah::*;
 use std::error::Error;
 
+use if_addrs::{IfAddr, Interface};
+use std::collections::HashSet;
 use std::fs;
-use std::io::Write;
-use std::net::{Shutdown, UdpSocket};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// run the nyah daemon
+struct HostArgs {
+    #[argh(option, default = "\"0.0.0.0:25565\".parse().unwrap()")]
+    /// the address to listen on. use [::]:25565 for ipv6 (and dual-stack) discovery
+    bind: SocketAddr,
+    #[argh(option)]
+    /// a multicast group to discover peers on, e.g. 239.255.42.99:25565 or [ff02::4e79]:25565 (can be repeated)
+    multicast: Vec<SocketAddr>,
+    #[argh(switch)]
+    /// don't broadcast on each ipv4 interface to find peers
+    no_broadcast: bool,
+    #[argh(option, long = "peer")]
+    /// a peer to contact directly, for peers broadcasts can't reach (can be repeated)
+    peers: Vec<SocketAddr>,
+    #[argh(option, long = "tracker")]
+    /// a tracker to announce our boxes to and ask for peers (can be repeated)
+    trackers: Vec<SocketAddr>,
+    #[argh(option)]
+    /// where to keep the dht routing table between runs
+    dht_state: Option<PathBuf>,
+    #[argh(option)]
+    /// where to keep this node's key, so its node id survives restarts
+    identity: Option<PathBuf>,
+    #[argh(switch)]
+    /// refuse plaintext from peers, and don't talk to ones that can't encrypt (like opencomputers)
+    require_encryption: bool,
+    #[argh(option)]
+    /// only talk to peers in this address or cidr range (can be repeated)
+    allow: Vec<Cidr>,
+    #[argh(option)]
+    /// never talk to peers in this address or cidr range (can be repeated)
+    deny: Vec<Cidr>,
+    #[argh(option, default = "DEFAULT_UPLOAD_SLOTS")]
+    /// how many peers to upload each box to at once
+    upload_slots: usize,
+    #[argh(option, default = "DEFAULT_PEER_TIMEOUT.as_secs()")]
+    /// seconds of silence after which a peer is forgotten
+    peer_timeout: u64,
+    #[argh(option)]
+    /// how many threads hash new boxes and check existing files (default: one per core)
+    hash_threads: Option<usize>,
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let local_ip = local_ip_address::local_ip().unwrap();
+    let args: HostArgs = argh::from_env();
+    let interfaces = if_addrs::get_if_addrs()?;
+
+    let socket = UdpSocket::bind(args.bind)?;
+
+    let mut discovery_addrs = Vec::new();
+    if args.bind.is_ipv4() && !args.no_broadcast {
+        socket.set_broadcast(true)?;
+        discovery_addrs.extend(broadcast_addrs(&interfaces, args.bind.port()));
+    }
 
-    let socket = UdpSocket::bind("0.0.0.0:25565")?;
-    socket.set_broadcast(true)?;
+    for group in &args.multicast {
+        join_multicast(&socket, group.ip(), &interfaces)?;
+        discovery_addrs.push(*group);
+    }
 
-    let mut socket = LaminarSocket::bind_internal(socket, LaminarConfig::default())?;
+    let mut socket = LaminarSocket::bind_internal(
+        socket,
+        LaminarConfig {
+            // keep connections to live peers from idling out, so Timeout actually means dead
+            heartbeat_interval: Some(Duration::from_secs(1)),
+            // max_packet_size stays the default, which max_chunk_size goes by
+            ..LaminarConfig::default()
+        },
+    )?;
 
     let event_receiver = socket.get_event_receiver();
     let event_sender = socket.get_packet_sender();
 
     let _thread = std::thread::spawn(move || socket.start_polling());
 
-    let mut state = NyahState::new(event_sender, Some(local_ip));
+    let identity = match &args.identity {
+        Some(path) => Identity::load_or_create(path)?,
+        None => Identity::generate(),
+    };
+
+    let hash_threads = args
+        .hash_threads
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
 
-    fs::remove_file("/var/run/nyah.sock");
+    let mut state = NyahState::new(event_sender, identity, hash_threads);
+    state.require_encryption = args.require_encryption;
+    if let Some(path) = args.dht_state.as_ref().filter(|p| p.exists()) {
+        state.load_dht(path)?;
+    }
+    state.peer_timeout = Duration::from_secs(args.peer_timeout);
+    state.trackers = args.trackers;
+    let port = args.bind.port();
+    state.own_addrs = interfaces
+        .iter()
+        .map(|i| SocketAddr::new(i.ip(), port))
+        .chain(broadcast_addrs(&interfaces, port))
+        .collect();
+    state.upload_slots = args.upload_slots;
+    for cidr in args.allow {
+        state.add_acl_rule(AclKind::Allow, cidr);
+    }
+    for cidr in args.deny {
+        state.add_acl_rule(AclKind::Deny, cidr);
+    }
+    for peer in args.peers {
+        state.add_peer(peer)?;
+    }
+
+    // it's fine if there isn't one left over
+    let _ = fs::remove_file("/var/run/nyah.sock");
     let ipc_socket = UnixListener::bind("/var/run/nyah.sock")?;
     ipc_socket.set_nonblocking(true)?;
 
     let mut last_peer_search = Instant::now();
+    let mut last_announce: Option<Instant> = None;
+    let mut last_dht_tick = Instant::now();
+    let mut last_schedule = Instant::now();
+    let mut last_rechoke = Instant::now();
+
+    let mut running = true;
 
-    loop {
+    while running {
         if let Ok((mut peer, _)) = ipc_socket.accept() {
             use IPCCall::*;
 
             let call: IPCCall = rmp_serde::from_read(&mut peer).unwrap();
             let res = match call {
-                CreateBox(name, path) => {
-                    let hash = state.create_box(name, path)?;
-                    IPCResponse::BoxCreated(hash)
-                }
-                DownloadBox(hash, path) => {
-                    state.add_desired_box(hash, path);
+                // the daemon is the one that checks the options, see Layout::is_valid
+                CreateBox(name, path, options) => match state.create_box(name, path, options) {
+                    Ok(id) => IPCResponse::Creating(id),
+                    Err(e) => IPCResponse::failed(&e),
+                },
+                GetCreation(id) => match state.get_creation(id) {
+                    Some(Ok(s)) => IPCResponse::Box(s),
+                    Some(Err(e)) => IPCResponse::failed(&e),
+                    None => IPCResponse::NotFound,
+                },
+                DownloadBox(hash, path, strategy, passphrase) => {
+                    state.add_desired_box(hash, path, strategy, passphrase);
                     IPCResponse::Ok
                 }
+                SetPickStrategy(hash, strategy) => {
+                    if state.set_strategy(hash, strategy) {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
                 GetBoxState(hash) => {
                     if let Some(s) = state.boxes.get(&hash).map(|b| b.get_download_state()) {
                         IPCResponse::Box(s)
@@ -51,33 +173,198 @@ fn main() -> Result<(), Box<dyn Error>> {
                         IPCResponse::NotFound
                     }
                 }
-                GetAllPeers => IPCResponse::Peers(state.peers.iter().copied().collect()),
-                GetAllBoxes => IPCResponse::Boxes(
-                    state
+                SetSequential(hash, path, sequential) => {
+                    let set = state
                         .boxes
-                        .values()
-                        .map(|b| b.get_download_state())
-                        .collect(),
-                ),
+                        .get_mut(&hash)
+                        .map(|b| b.set_sequential(&path, sequential))
+                        .unwrap_or(false);
+                    if set {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
+                GetAllPeers => IPCResponse::Peers(state.get_peer_info()),
+                GetAllBoxes => IPCResponse::Boxes(state.get_box_states()),
+                RemoveBox(hash) => {
+                    if state.remove_box(hash)? {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
+                Shutdown => {
+                    state.cancel_all_downloads()?;
+                    running = false;
+                    IPCResponse::Ok
+                }
+                GetBans => IPCResponse::Bans(state.reputation.bans()),
+                ClearBans(ip) => {
+                    if state.reputation.clear(ip) {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
+                GetAcl => IPCResponse::Acl(state.acl.clone()),
+                // rules over ipc haven't been through Cidr's parser, so check them here
+                AddAclRule(kind, cidr) => match Cidr::new(cidr.addr, cidr.prefix) {
+                    Ok(cidr) => {
+                        state.add_acl_rule(kind, cidr);
+                        IPCResponse::Ok
+                    }
+                    Err(e) => IPCResponse::failed(&io::Error::new(io::ErrorKind::InvalidInput, e)),
+                },
+                RemoveAclRule(kind, cidr) => match Cidr::new(cidr.addr, cidr.prefix) {
+                    Ok(cidr) if state.acl.remove(kind, cidr) => IPCResponse::Ok,
+                    Ok(_) => IPCResponse::NotFound,
+                    Err(e) => IPCResponse::failed(&io::Error::new(io::ErrorKind::InvalidInput, e)),
+                },
+                Publish(key_path, name, path) => match state.publish(key_path, name, path) {
+                    Ok(id) => IPCResponse::Creating(id),
+                    Err(e) => IPCResponse::failed(&e),
+                },
+                Subscribe(id, path, strategy) => {
+                    state.subscribe(id, path, strategy);
+                    state.poll_channels()?;
+                    IPCResponse::Ok
+                }
+                Unsubscribe(id) => {
+                    if state.unsubscribe(id) {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
+                GetChannels => IPCResponse::Channels(state.get_channel_info()),
+                AddPeer(addr) => {
+                    if state.add_peer(addr)? {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::Denied
+                    }
+                }
+                RemovePeer(addr) => {
+                    if state.remove_peer(addr) {
+                        IPCResponse::Ok
+                    } else {
+                        IPCResponse::NotFound
+                    }
+                }
             };
 
             rmp_serde::encode::write(&mut peer, &res).unwrap();
             peer.flush()?;
-            peer.shutdown(Shutdown::Both)?;
+            peer.shutdown(std::net::Shutdown::Both)?;
         }
 
         if (state.peers.is_empty() && last_peer_search.elapsed() > Duration::from_secs(20))
             || last_peer_search.elapsed() > Duration::from_secs(20)
         {
-            state.search_for_peers("255.255.255.255:25565".parse().unwrap())?;
+            for addr in &discovery_addrs {
+                state.search_for_peers(*addr)?;
+            }
+            state.contact_static_peers()?;
+            state.ping_peers()?;
+            state.exchange_peers()?;
+            state.refresh_bitfields()?;
+            state.poll_channels()?;
+            state.expire_peers();
             last_peer_search = Instant::now();
         }
 
-        if let Ok(event) = event_receiver.recv_timeout(Duration::from_millis(400)) {
-            state.handle_packet(event)?;
+        if last_announce
+            .map(|t| t.elapsed() > ANNOUNCE_INTERVAL)
+            .unwrap_or(true)
+        {
+            state.announce_to_trackers()?;
+            state.query_trackers()?;
+            state.search_dht()?;
+            if let Some(path) = &args.dht_state {
+                state.save_dht(path)?;
+            }
+            last_announce = Some(Instant::now());
+        }
+
+        if last_dht_tick.elapsed() > Duration::from_secs(1) {
+            state.tick_dht()?;
+            last_dht_tick = Instant::now();
+        }
+
+        // wake up in time for the next chunk when we're uploading
+        let timeout = if state.uploads.is_empty() {
+            Duration::from_millis(400)
         } else {
+            UPLOAD_CHUNK_INTERVAL
+        };
+
+        if let Ok(event) = event_receiver.recv_timeout(timeout) {
+            state.handle_packet(event)?;
+        }
+
+        state.process_uploads()?;
+        state.poll_hashing()?;
+
+        if last_rechoke.elapsed() > RECHOKE_INTERVAL {
+            state.rechoke()?;
+            last_rechoke = Instant::now();
+        }
+
+        // on a timer rather than when idle, so busy transfers don't starve the scheduler
+        if last_schedule.elapsed() > Duration::from_secs(1) {
             state.search_for_metadata()?;
             state.search_for_pieces()?;
+            last_schedule = Instant::now();
+        }
+    }
+
+    // give the socket thread a moment to get our cancels out
+    std::thread::sleep(Duration::from_millis(500));
+    fs::remove_file("/var/run/nyah.sock")?;
+
+    Ok(())
+}
+
+fn broadcast_addrs(interfaces: &[Interface], port: u16) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = interfaces
+        .iter()
+        .filter(|i| !i.is_loopback())
+        .filter_map(|i| match &i.addr {
+            IfAddr::V4(v4) => v4.broadcast.map(|b| SocketAddr::new(IpAddr::V4(b), port)),
+            _ => None,
+        })
+        .collect();
+
+    if addrs.is_empty() {
+        addrs.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port));
+    }
+
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+// joins the group on every interface that can reach it, so multi-homed hosts hear all of them
+fn join_multicast(socket: &UdpSocket, group: IpAddr, interfaces: &[Interface]) -> io::Result<()> {
+    let mut joined = HashSet::new();
+
+    for iface in interfaces.iter().filter(|i| !i.is_loopback()) {
+        match (group, &iface.addr) {
+            (IpAddr::V4(group), IfAddr::V4(v4)) if joined.insert(&iface.name) => {
+                socket.join_multicast_v4(&group, &v4.ip)?;
+            }
+            (IpAddr::V6(group), IfAddr::V6(_)) if joined.insert(&iface.name) => {
+                socket.join_multicast_v6(&group, iface.index.unwrap_or(0))?;
+            }
+            _ => (),
+        }
+    }
+
+    if joined.is_empty() {
+        match group {
+            IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
         }
     }
 
@@ -1,9 +1,17 @@
 use argh::FromArgs;
+use nyah::acl::{AclKind, Cidr};
+use nyah::channel::{generate_key, load_key, ChannelId};
+use nyah::hash::HashAlgorithm;
+use nyah::hashing::CreationId;
+use nyah::picker::PickStrategy;
 use nyah::*;
 use std::fs;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
 
 use libhumancode::{decode_chunk, encode_chunk};
 const HASH_ECC_SYMBOLS: u8 = 5;
@@ -22,8 +30,16 @@ enum SubCommand {
     CreateBox(CreateBoxCmd),
     DownloadBox(DownloadBoxCmd),
     GetBoxState(GetBoxStateCmd),
+    SetStrategy(SetStrategyCmd),
+    Stream(StreamCmd),
     GetAllBoxes(GetAllBoxesCmd),
     GetAllPeers(GetAllPeersCmd),
+    Peer(PeerCmd),
+    RemoveBox(RemoveBoxCmd),
+    Shutdown(ShutdownCmd),
+    Bans(BansCmd),
+    Acl(AclCmd),
+    Channel(ChannelCmd),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -36,6 +52,33 @@ struct CreateBoxCmd {
     #[argh(positional)]
     /// the place to look for the box's files
     path: PathBuf,
+    #[argh(option)]
+    /// makes the box private: peers need this passphrase to download it
+    passphrase: Option<String>,
+    #[argh(switch)]
+    /// keep the metadata small by listing a merkle root per file instead of every piece
+    /// hash. older peers can't download these
+    merkle: bool,
+    #[argh(switch)]
+    /// hash each chunk, so downloaders catch a bad chunk (and who sent it) as soon as it
+    /// arrives. older peers can't download these
+    chunk_hashes: bool,
+    #[argh(option)]
+    /// bytes per piece. bigger pieces mean fewer hashes, but more to redo when one's bad
+    /// (defaults to 48000, for opencomputers)
+    piece_size: Option<usize>,
+    #[argh(option)]
+    /// bytes per chunk, at most what fits in one packet (16128 with laminar's defaults).
+    /// bigger chunks are much faster between real machines (defaults to 256, for
+    /// opencomputers)
+    chunk_size: Option<usize>,
+    #[argh(option, default = "HashAlgorithm::default()")]
+    /// blake2s128 (the default, which opencomputers can do) or the longer, harder to
+    /// collide blake2s256
+    hash: HashAlgorithm,
+    #[argh(switch)]
+    /// return as soon as hashing starts, rather than waiting for the box's hash
+    no_wait: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -46,6 +89,12 @@ struct DownloadBoxCmd {
     hash: String,
     #[argh(positional)]
     path: PathBuf,
+    #[argh(option, default = "PickStrategy::RarestFirst")]
+    /// the order to download pieces in: rarest (default), sequential or random
+    strategy: PickStrategy,
+    #[argh(option)]
+    /// the passphrase, if it's a private box
+    passphrase: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -56,11 +105,222 @@ struct GetBoxStateCmd {
     hash: String,
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "set-strategy")]
+/// changes the order a box's pieces are downloaded in.
+struct SetStrategyCmd {
+    #[argh(positional)]
+    hash: String,
+    #[argh(positional)]
+    /// rarest, sequential or random
+    strategy: PickStrategy,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "stream")]
+/// downloads a file in a box in order, so it can be read before it's done.
+struct StreamCmd {
+    #[argh(positional)]
+    hash: String,
+    #[argh(positional)]
+    /// the file's path inside the box
+    path: PathBuf,
+    #[argh(switch)]
+    /// go back to downloading the file normally
+    off: bool,
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "list-peers")]
 /// gets all peers known to Nyah.
 struct GetAllPeersCmd {}
 
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "remove")]
+/// stops downloading or seeding a box. its files are kept.
+struct RemoveBoxCmd {
+    #[argh(positional)]
+    hash: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "shutdown")]
+/// stops the nyah daemon, cancelling any transfers in progress.
+struct ShutdownCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "bans")]
+/// lists or clears peers banned for sending bad data.
+struct BansCmd {
+    #[argh(subcommand)]
+    cmd: BansSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum BansSubCommand {
+    List(ListBansCmd),
+    Clear(ClearBansCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+/// lists banned peers.
+struct ListBansCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "clear")]
+/// unbans every peer on an ip, or everyone if no ip is given.
+struct ClearBansCmd {
+    #[argh(positional)]
+    ip: Option<IpAddr>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "acl")]
+/// views or edits which addresses nyah will talk to.
+struct AclCmd {
+    #[argh(subcommand)]
+    cmd: AclSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum AclSubCommand {
+    List(ListAclCmd),
+    Allow(AllowCmd),
+    Deny(DenyCmd),
+    Remove(RemoveAclCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+/// lists the allow and deny rules.
+struct ListAclCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "allow")]
+/// only talk to this address or cidr range (and any others allowed).
+struct AllowCmd {
+    #[argh(positional)]
+    cidr: Cidr,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "deny")]
+/// never talk to this address or cidr range.
+struct DenyCmd {
+    #[argh(positional)]
+    cidr: Cidr,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "remove")]
+/// removes an allow or deny rule.
+struct RemoveAclCmd {
+    #[argh(positional)]
+    /// allow or deny
+    kind: AclKind,
+    #[argh(positional)]
+    cidr: Cidr,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "channel")]
+/// publishes to or follows channels: boxes that update under a fixed id.
+struct ChannelCmd {
+    #[argh(subcommand)]
+    cmd: ChannelSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum ChannelSubCommand {
+    New(NewChannelCmd),
+    Publish(PublishCmd),
+    Subscribe(SubscribeCmd),
+    Unsubscribe(UnsubscribeCmd),
+    List(ListChannelsCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "new")]
+/// makes a new channel, keeping its publishing key in the given file.
+struct NewChannelCmd {
+    #[argh(positional)]
+    key: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "publish")]
+/// makes a box from a folder the newest revision of a channel.
+struct PublishCmd {
+    #[argh(positional)]
+    /// the channel's key file
+    key: PathBuf,
+    #[argh(positional)]
+    name: String,
+    #[argh(positional)]
+    path: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "subscribe")]
+/// keeps a folder up to date with a channel's newest revision.
+struct SubscribeCmd {
+    #[argh(positional)]
+    id: String,
+    #[argh(positional)]
+    path: PathBuf,
+    #[argh(option, default = "PickStrategy::RarestFirst")]
+    /// the order to download pieces in: rarest (default), sequential or random
+    strategy: PickStrategy,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "unsubscribe")]
+/// stops following (or publishing to) a channel.
+struct UnsubscribeCmd {
+    #[argh(positional)]
+    id: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "list")]
+/// lists the channels we publish to or follow.
+struct ListChannelsCmd {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "peer")]
+/// manually adds or removes peers.
+struct PeerCmd {
+    #[argh(subcommand)]
+    cmd: PeerSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum PeerSubCommand {
+    Add(AddPeerCmd),
+    Remove(RemovePeerCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "add")]
+/// adds a peer, which nyah will contact now and periodically.
+struct AddPeerCmd {
+    #[argh(positional)]
+    addr: SocketAddr,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "remove")]
+/// removes a peer.
+struct RemovePeerCmd {
+    #[argh(positional)]
+    addr: SocketAddr,
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "status")]
 /// gets all boxes currently being downloaded or seeded.
@@ -74,32 +334,73 @@ fn main() -> io::Result<()> {
     let RPCArgs { cmd } = argh::from_env();
     use SubCommand::*;
     match cmd {
-        CreateBox(CreateBoxCmd { name, path }) => {
-            let path = fs::canonicalize(path)?;
-
-            if let Ok(IPCResponse::BoxCreated(hash)) = call(IPCCall::CreateBox(name, path)) {
+        CreateBox(CreateBoxCmd {
+            name,
+            path,
+            passphrase,
+            merkle,
+            chunk_hashes,
+            piece_size,
+            chunk_size,
+            hash,
+            no_wait,
+        }) => {
+            if let Some(chunk_size) = chunk_size.filter(|&c| c > max_chunk_size()) {
                 println!(
-                    "created box! here's it's hash: {}",
-                    encode_chunk(&hash, HASH_ECC_SYMBOLS, HASH_BITS)
-                        .unwrap()
-                        .pretty()
-                        .as_str()
+                    "chunks can be at most {} bytes, not {} >:",
+                    max_chunk_size(),
+                    chunk_size
                 );
-            } else {
-                println!("couldn't create box >:");
+                return Ok(());
+            }
+
+            let path = fs::canonicalize(path)?;
+            let options = BoxOptions {
+                passphrase,
+                merkle,
+                chunk_hashes,
+                piece_size,
+                chunk_size,
+                hash_algorithm: hash,
+            };
+
+            let id = match call(IPCCall::CreateBox(name, path, options)) {
+                Ok(IPCResponse::Creating(id)) => id,
+                Ok(IPCResponse::Failed(e)) => {
+                    println!("couldn't create box ({}) >:", e);
+                    return Ok(());
+                }
+                _ => {
+                    println!("couldn't create box >:");
+                    return Ok(());
+                }
+            };
+
+            if no_wait {
+                println!("hashing the box! check `status` for how it's going");
+                return Ok(());
+            }
+
+            match wait_for_creation(id)? {
+                Ok(hash) => println!("created box! here's it's hash: {}", encode_hash(&hash)),
+                Err(e) => println!("couldn't hash the box's files ({}) >:", e),
             }
         }
-        DownloadBox(DownloadBoxCmd { hash, path }) => {
+        DownloadBox(DownloadBoxCmd {
+            hash,
+            path,
+            strategy,
+            passphrase,
+        }) => {
             fs::create_dir_all(&path)?;
             let path = fs::canonicalize(&path)?;
             println!("{:?}", path);
 
-            let (decoded, _corrected) = decode_chunk(&hash, HASH_ECC_SYMBOLS, HASH_BITS)
-                .expect("weird! i couldn't decode the hash you gave me.");
-
             if let Ok(IPCResponse::Ok) = call(IPCCall::DownloadBox(
-                decoded.as_bytes().try_into().unwrap(),
+                decode_hash(&hash),
                 path,
+                strategy,
+                passphrase,
             )) {
                 println!("downloading box!");
             } else {
@@ -107,21 +408,33 @@ fn main() -> io::Result<()> {
             }
         }
         GetBoxState(GetBoxStateCmd { hash }) => {
-            let (decoded, _corrected) = decode_chunk(&hash, HASH_ECC_SYMBOLS, HASH_BITS)
-                .expect("weird! i couldn't decode the hash you gave me.");
-
-            match call(IPCCall::GetBoxState(decoded.as_bytes().try_into().unwrap()))? {
+            match call(IPCCall::GetBoxState(decode_hash(&hash)))? {
                 IPCResponse::Box(state) => display_box_verbose(state),
                 IPCResponse::NotFound => println!("box not found - if you recently added it, we may not have metadata for it yet!"),
                 _ => unreachable!()
             }
         }
+        SetStrategy(SetStrategyCmd { hash, strategy }) => {
+            match call(IPCCall::SetPickStrategy(decode_hash(&hash), strategy))? {
+                IPCResponse::Ok => println!("now downloading {}!", strategy),
+                IPCResponse::NotFound => println!("box not found!"),
+                _ => unreachable!(),
+            }
+        }
+        Stream(StreamCmd { hash, path, off }) => {
+            match call(IPCCall::SetSequential(decode_hash(&hash), path, !off))? {
+                IPCResponse::Ok if off => println!("no longer streaming!"),
+                IPCResponse::Ok => println!("streaming! check `details` for how much is ready"),
+                IPCResponse::NotFound => println!("couldn't find that box or file >:"),
+                _ => unreachable!(),
+            }
+        }
         GetAllBoxes(GetAllBoxesCmd { verbose }) => {
             if let IPCResponse::Boxes(states) = call(IPCCall::GetAllBoxes)? {
                 if verbose {
                     for s in states {
                         display_box_verbose(s);
-                        println!("")
+                        println!()
                     }
                 } else {
                     for s in states {
@@ -134,15 +447,225 @@ fn main() -> io::Result<()> {
             if let IPCResponse::Peers(peers) = call(IPCCall::GetAllPeers)? {
                 println!("current peers:");
                 for peer in peers {
-                    println!("> {}", peer);
+                    println!(
+                        "> {} (v{}{}) - {:?}, last seen {}s ago, penalty {}{}",
+                        peer.addr,
+                        peer.version,
+                        if peer.encrypted { ", encrypted" } else { "" },
+                        peer.state,
+                        peer.last_seen_secs,
+                        peer.penalty,
+                        peer.rtt_ms
+                            .map(|rtt| format!(", rtt {}ms", rtt))
+                            .unwrap_or_default()
+                    );
                 }
             }
         }
+        RemoveBox(RemoveBoxCmd { hash }) => match call(IPCCall::RemoveBox(decode_hash(&hash)))? {
+            IPCResponse::Ok => println!("removed box!"),
+            IPCResponse::NotFound => println!("box not found!"),
+            _ => unreachable!(),
+        },
+        Shutdown(_) => {
+            if let Ok(IPCResponse::Ok) = call(IPCCall::Shutdown) {
+                println!("bye!");
+            } else {
+                println!("couldn't shut nyah down >:");
+            }
+        }
+        Bans(BansCmd { cmd }) => match cmd {
+            BansSubCommand::List(_) => {
+                if let IPCResponse::Bans(bans) = call(IPCCall::GetBans)? {
+                    println!("banned peers:");
+                    for ban in bans {
+                        println!(
+                            "> {} - banned {}s ago, penalty {}",
+                            ban.addr, ban.banned_secs, ban.penalty
+                        );
+                    }
+                }
+            }
+            BansSubCommand::Clear(ClearBansCmd { ip }) => match call(IPCCall::ClearBans(ip))? {
+                IPCResponse::Ok => println!("cleared!"),
+                IPCResponse::NotFound => println!("nobody to unban!"),
+                _ => unreachable!(),
+            },
+        },
+        Acl(AclCmd { cmd }) => match cmd {
+            AclSubCommand::List(_) => {
+                if let IPCResponse::Acl(acl) = call(IPCCall::GetAcl)? {
+                    if acl.allow.is_empty() {
+                        println!("allowing everyone not denied");
+                    } else {
+                        println!("allowing:");
+                        for cidr in acl.allow {
+                            println!("> {}", cidr);
+                        }
+                    }
+
+                    println!("denying:");
+                    for cidr in acl.deny {
+                        println!("> {}", cidr);
+                    }
+                }
+            }
+            AclSubCommand::Allow(AllowCmd { cidr }) => {
+                if let Ok(IPCResponse::Ok) = call(IPCCall::AddAclRule(AclKind::Allow, cidr)) {
+                    println!("allowing {}!", cidr);
+                } else {
+                    println!("couldn't add rule >:");
+                }
+            }
+            AclSubCommand::Deny(DenyCmd { cidr }) => {
+                if let Ok(IPCResponse::Ok) = call(IPCCall::AddAclRule(AclKind::Deny, cidr)) {
+                    println!("denying {}!", cidr);
+                } else {
+                    println!("couldn't add rule >:");
+                }
+            }
+            AclSubCommand::Remove(RemoveAclCmd { kind, cidr }) => {
+                match call(IPCCall::RemoveAclRule(kind, cidr))? {
+                    IPCResponse::Ok => println!("removed rule!"),
+                    IPCResponse::NotFound => println!("no such rule!"),
+                    IPCResponse::Failed(e) => println!("couldn't remove rule ({}) >:", e),
+                    _ => unreachable!(),
+                }
+            }
+        },
+        Channel(ChannelCmd { cmd }) => match cmd {
+            ChannelSubCommand::New(NewChannelCmd { key }) => {
+                let key = generate_key(&key)?;
+                println!(
+                    "made a channel! here's its id: {}",
+                    hex::encode(key.verifying_key().to_bytes())
+                );
+            }
+            ChannelSubCommand::Publish(PublishCmd { key, name, path }) => {
+                let key = fs::canonicalize(key)?;
+                let path = fs::canonicalize(path)?;
+                let channel = load_key(&key)?.verifying_key().to_bytes();
+
+                let id = match call(IPCCall::Publish(key, name, path)) {
+                    Ok(IPCResponse::Creating(id)) => id,
+                    Ok(IPCResponse::Failed(e)) => {
+                        println!("couldn't publish ({}) >:", e);
+                        return Ok(());
+                    }
+                    _ => {
+                        println!("couldn't publish >:");
+                        return Ok(());
+                    }
+                };
+
+                match wait_for_creation(id)? {
+                    Ok(hash) => println!(
+                        "published to {}! its box hash is {}",
+                        hex::encode(channel),
+                        encode_hash(&hash)
+                    ),
+                    Err(e) => println!("couldn't hash the box's files ({}) >:", e),
+                }
+            }
+            ChannelSubCommand::Subscribe(SubscribeCmd { id, path, strategy }) => {
+                fs::create_dir_all(&path)?;
+                let path = fs::canonicalize(&path)?;
+
+                if let Ok(IPCResponse::Ok) =
+                    call(IPCCall::Subscribe(decode_channel(&id), path, strategy))
+                {
+                    println!("following channel!");
+                } else {
+                    println!("couldn't follow channel >:");
+                }
+            }
+            ChannelSubCommand::Unsubscribe(UnsubscribeCmd { id }) => {
+                match call(IPCCall::Unsubscribe(decode_channel(&id)))? {
+                    IPCResponse::Ok => println!("unsubscribed!"),
+                    IPCResponse::NotFound => println!("channel not found!"),
+                    _ => unreachable!(),
+                }
+            }
+            ChannelSubCommand::List(_) => {
+                if let IPCResponse::Channels(channels) = call(IPCCall::GetChannels)? {
+                    println!("current channels:");
+                    for channel in channels {
+                        println!(
+                            "> {}{}",
+                            hex::encode(channel.id),
+                            if channel.publishing {
+                                " (publishing)"
+                            } else {
+                                ""
+                            }
+                        );
+
+                        match (channel.seq, channel.box_hash) {
+                            (Some(seq), Some(hash)) => {
+                                println!("  revision {}, box {}", seq, encode_hash(&hash))
+                            }
+                            _ => println!("  no revisions found yet"),
+                        }
+
+                        if let Some(path) = channel.subscribed {
+                            println!("  following into {}", path.display());
+                        }
+                    }
+                }
+            }
+        },
+        Peer(PeerCmd { cmd }) => match cmd {
+            PeerSubCommand::Add(AddPeerCmd { addr }) => match call(IPCCall::AddPeer(addr)) {
+                Ok(IPCResponse::Ok) => println!("added peer {}!", addr),
+                Ok(IPCResponse::Denied) => println!("the acl doesn't allow {} >:", addr),
+                _ => println!("couldn't add peer >:"),
+            },
+            PeerSubCommand::Remove(RemovePeerCmd { addr }) => {
+                match call(IPCCall::RemovePeer(addr))? {
+                    IPCResponse::Ok => println!("removed peer {}!", addr),
+                    IPCResponse::NotFound => println!("i don't know that peer!"),
+                    _ => unreachable!(),
+                }
+            }
+        },
     }
 
     Ok(())
 }
 
+fn decode_channel(id: &str) -> ChannelId {
+    hex::decode(id)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .expect("weird! that doesn't look like a channel id.")
+}
+
+// a humancode chunk only holds 128 bits, so longer hashes are a few chunks joined by +
+fn encode_hash(hash: &BoxHash) -> String {
+    hash.as_bytes()
+        .chunks(HASH_BITS as usize / 8)
+        .map(|part| {
+            encode_chunk(part, HASH_ECC_SYMBOLS, HASH_BITS)
+                .unwrap()
+                .pretty()
+                .as_str()
+                .to_owned()
+        })
+        .collect::<Vec<String>>()
+        .join("+")
+}
+
+fn decode_hash(hash: &str) -> BoxHash {
+    let mut bytes = Vec::new();
+    for part in hash.split('+') {
+        let (decoded, _corrected) = decode_chunk(part, HASH_ECC_SYMBOLS, HASH_BITS)
+            .expect("weird! i couldn't decode the hash you gave me.");
+        bytes.extend_from_slice(decoded.as_bytes());
+    }
+
+    BoxHash::from_slice(&bytes).expect("weird! that hash is the wrong length.")
+}
+
 fn call(msg: IPCCall) -> io::Result<IPCResponse> {
     let mut stream = UnixStream::connect("/var/run/nyah.sock")?;
     rmp_serde::encode::write(&mut stream, &msg).unwrap();
@@ -150,24 +673,76 @@ fn call(msg: IPCCall) -> io::Result<IPCResponse> {
     Ok(rmp_serde::from_read(&mut stream).unwrap())
 }
 
+// polls until the daemon's done hashing a new box, or says why it couldn't
+fn wait_for_creation(id: CreationId) -> io::Result<Result<BoxHash, String>> {
+    loop {
+        let state = match call(IPCCall::GetCreation(id))? {
+            IPCResponse::Box(state) => state,
+            IPCResponse::Failed(e) => return Ok(Err(e)),
+            _ => return Ok(Err("it went missing".to_owned())),
+        };
+
+        if let Some(hash) = state.box_hash {
+            return Ok(Ok(hash));
+        }
+
+        if let Some((hashed, total)) = state.hashing {
+            println!("hashing... {}/{} pieces", hashed, total);
+        }
+
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn display_hash(state: &BoxState) -> String {
+    match (state.box_hash, state.hashing) {
+        (Some(hash), _) => encode_hash(&hash),
+        (None, Some((hashed, total))) => format!("not yet, {}/{} pieces hashed", hashed, total),
+        (None, None) => "not yet".to_owned(),
+    }
+}
+
 fn display_box_verbose(state: BoxState) {
     println!(
-        "cat box {}\n(hash {})",
+        "cat box {}\n(hash {})\ndownloading {}",
         state.name,
-        encode_chunk(&state.box_hash, HASH_ECC_SYMBOLS, HASH_BITS)
-            .unwrap()
-            .pretty()
-            .as_str()
+        display_hash(&state),
+        state.strategy
     );
 
+    if let (Some(_), Some((hashed, total))) = (state.box_hash, state.hashing) {
+        println!(
+            "checking files already on disk, {}/{} pieces",
+            hashed, total
+        );
+    }
+
+    if state.private {
+        println!("private, peers need the passphrase");
+    }
+
+    if state.bytes_saved > 0 {
+        println!(
+            "{} bytes copied from other boxes instead of downloaded",
+            state.bytes_saved
+        );
+    }
+
     for entry in state.files {
         println!(
             "> {} - {}% done ({}/{} pieces)",
             entry.path.into_os_string().to_str().unwrap(),
-            (entry.pieces_downloaded * 100) / entry.total_pieces,
+            percent_done(entry.pieces_downloaded, entry.total_pieces),
             entry.pieces_downloaded,
             entry.total_pieces
         );
+
+        if entry.sequential {
+            println!(
+                "  streaming, first {}/{} bytes ready",
+                entry.contiguous_bytes, entry.size
+            );
+        }
     }
 }
 
@@ -179,12 +754,14 @@ fn display_box_min(state: BoxState) {
     println!(
         "cat box {}\n(hash {})\n> {}% done ({}/{} pieces)",
         state.name,
-        encode_chunk(&state.box_hash, HASH_ECC_SYMBOLS, HASH_BITS)
-            .unwrap()
-            .pretty()
-            .as_str(),
-        (done * 100) / total,
+        display_hash(&state),
+        percent_done(done, total),
         done,
         total
     );
 }
+
+// an empty file (or box) has nothing left to download
+fn percent_done(done: usize, total: usize) -> usize {
+    (done * 100).checked_div(total).unwrap_or(100)
+}
@@ -0,0 +1,46 @@
+use argh::FromArgs;
+use laminar::{Config as LaminarConfig, Socket as LaminarSocket};
+use nyah::tracker::*;
+use std::error::Error;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run a nyah tracker, which tells nodes who else holds the boxes they want
+struct TrackerArgs {
+    #[argh(option, default = "\"0.0.0.0:25566\".parse().unwrap()")]
+    /// the address to listen on
+    bind: SocketAddr,
+    #[argh(option, default = "DEFAULT_TRACKER_TTL.as_secs()")]
+    /// seconds after which a node that stopped announcing is forgotten
+    ttl: u64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: TrackerArgs = argh::from_env();
+
+    let socket = UdpSocket::bind(args.bind)?;
+    let mut socket = LaminarSocket::bind_internal(socket, LaminarConfig::default())?;
+
+    let event_receiver = socket.get_event_receiver();
+    let event_sender = socket.get_packet_sender();
+
+    let _thread = std::thread::spawn(move || socket.start_polling());
+
+    let mut tracker = Tracker::new(event_sender);
+    tracker.ttl = Duration::from_secs(args.ttl);
+
+    let mut last_expiry = Instant::now();
+
+    loop {
+        if let Ok(event) = event_receiver.recv_timeout(Duration::from_millis(400)) {
+            tracker.handle_packet(event)?;
+        }
+
+        if last_expiry.elapsed() > Duration::from_secs(20) {
+            tracker.expire();
+            last_expiry = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::*;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// a channel is named by its publisher's public key
+pub type ChannelId = [u8; 32];
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Revision {
+    pub seq: u64, // only ever goes up, newest wins
+    pub box_hash: BoxHash,
+    pub metadata: CardboardMetadata,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedRevision {
+    pub revision: Revision,
+    pub signature: Vec<u8>,
+}
+
+impl SignedRevision {
+    pub fn sign(key: &SigningKey, revision: Revision) -> SignedRevision {
+        let id = key.verifying_key().to_bytes();
+        let signature = key.sign(&signed_bytes(&id, &revision));
+
+        SignedRevision {
+            revision,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn verify(&self, id: &ChannelId) -> bool {
+        let key = match VerifyingKey::from_bytes(id) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_slice(&self.signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        key.verify_strict(&signed_bytes(id, &self.revision), &signature)
+            .is_ok()
+    }
+}
+
+// the channel goes in too, so a revision can't be passed off as another channel's
+fn signed_bytes(id: &ChannelId, revision: &Revision) -> Vec<u8> {
+    rmp_serde::to_vec(&(b"nyah channel", id, revision)).unwrap()
+}
+
+pub fn generate_key(path: impl AsRef<Path>) -> io::Result<SigningKey> {
+    let key = SigningKey::generate(&mut rand::thread_rng());
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(key.as_bytes())?;
+
+    Ok(key)
+}
+
+pub fn load_key(path: impl AsRef<Path>) -> io::Result<SigningKey> {
+    let bytes: [u8; 32] = fs::read(path)?
+        .try_into()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+    Ok(SigningKey::from_bytes(&bytes))
+}
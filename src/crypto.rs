@@ -0,0 +1,202 @@
+use crate::*;
+
+use blake2::Blake2s256;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::cell::Cell;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// how far behind the newest packet we'll still take one, since laminar doesn't keep order
+const REPLAY_WINDOW: u64 = 64;
+
+// a node's long-term key. its node id is derived from the public half, so a peer can't
+// claim someone else's node id once we've done a handshake with it
+pub struct Identity {
+    secret: StaticSecret,
+    pub public: PublicKey,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        Identity {
+            public: PublicKey::from(&secret),
+            secret,
+        }
+    }
+
+    pub fn load_or_create(path: impl AsRef<Path>) -> io::Result<Identity> {
+        if path.as_ref().exists() {
+            let bytes: [u8; 32] = fs::read(path)?
+                .try_into()
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            let secret = StaticSecret::from(bytes);
+
+            return Ok(Identity {
+                public: PublicKey::from(&secret),
+                secret,
+            });
+        }
+
+        let identity = Identity::generate();
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(identity.secret.as_bytes())?;
+
+        Ok(identity)
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        node_id_for(&self.public)
+    }
+}
+
+pub fn node_id_for(key: &PublicKey) -> NodeId {
+    Blake2s16::digest(key.as_bytes()).into()
+}
+
+pub fn ephemeral() -> StaticSecret {
+    StaticSecret::random_from_rng(rand::thread_rng())
+}
+
+// keys for one peer, from a handshake mixing both sides' static and ephemeral keys.
+// sending and receiving use different keys, so the counters can't collide
+pub struct Session {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    sent: Cell<u64>,
+    next_recv: u64,   // one past the highest counter we've accepted
+    recv_window: u64, // bit n set if we've accepted next_recv - 1 - n
+}
+
+impl Session {
+    // None if their keys were degenerate
+    pub fn new(
+        initiator: bool,
+        identity: &Identity,
+        ephemeral: &StaticSecret,
+        their_static: &PublicKey,
+        their_ephemeral: &PublicKey,
+    ) -> Option<Session> {
+        let ee = ephemeral.diffie_hellman(their_ephemeral);
+        let (es, se) = if initiator {
+            (
+                ephemeral.diffie_hellman(their_static),
+                identity.secret.diffie_hellman(their_ephemeral),
+            )
+        } else {
+            (
+                identity.secret.diffie_hellman(their_ephemeral),
+                ephemeral.diffie_hellman(their_static),
+            )
+        };
+
+        if !(ee.was_contributory() && es.was_contributory() && se.was_contributory()) {
+            return None;
+        }
+
+        let ours = (identity.public, PublicKey::from(ephemeral));
+        let theirs = (*their_static, *their_ephemeral);
+        let (i, r) = if initiator {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+
+        let mut hasher = Blake2s256::new();
+        hasher.update(b"nyah session");
+        for part in [ee.as_bytes(), es.as_bytes(), se.as_bytes()] {
+            hasher.update(part);
+        }
+        for key in [i.0, i.1, r.0, r.1] {
+            hasher.update(key.as_bytes());
+        }
+        let key = hasher.finalize();
+
+        let direction = |label: &[u8]| {
+            let mut hasher = Blake2s256::new();
+            hasher.update(key);
+            hasher.update(label);
+            ChaCha20Poly1305::new(&hasher.finalize())
+        };
+        let (to_responder, to_initiator) = (direction(b"i2r"), direction(b"r2i"));
+
+        Some(if initiator {
+            Session {
+                send: to_responder,
+                recv: to_initiator,
+                sent: Cell::new(0),
+                next_recv: 0,
+                recv_window: 0,
+            }
+        } else {
+            Session {
+                send: to_initiator,
+                recv: to_responder,
+                sent: Cell::new(0),
+                next_recv: 0,
+                recv_window: 0,
+            }
+        })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> (u64, Vec<u8>) {
+        let counter = self.sent.get();
+        self.sent.set(counter + 1);
+
+        let ciphertext = self
+            .send
+            .encrypt(&nonce(counter), plaintext)
+            .expect("encrypting into a vec can't fail");
+
+        (counter, ciphertext)
+    }
+
+    pub fn open(&mut self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if self.replayed(counter) {
+            return None;
+        }
+
+        let plaintext = self.recv.decrypt(&nonce(counter), ciphertext).ok()?;
+        self.accept(counter);
+
+        Some(plaintext)
+    }
+
+    fn replayed(&self, counter: u64) -> bool {
+        if counter >= self.next_recv {
+            return false;
+        }
+
+        let age = self.next_recv - 1 - counter;
+        age >= REPLAY_WINDOW || self.recv_window & (1 << age) != 0
+    }
+
+    fn accept(&mut self, counter: u64) {
+        if counter >= self.next_recv {
+            let shift = counter - self.next_recv + 1;
+            self.recv_window = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.recv_window << shift
+            };
+            self.recv_window |= 1;
+            self.next_recv = counter + 1;
+        } else {
+            self.recv_window |= 1 << (self.next_recv - 1 - counter);
+        }
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
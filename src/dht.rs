@@ -0,0 +1,402 @@
+use crate::*;
+
+use crossbeam_channel::Sender;
+use laminar::Packet as LaminarPacket;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const K: usize = 8; // bucket size, and how many nodes hold each provider record
+pub const ALPHA: usize = 3; // queries in flight per lookup
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+pub const PROVIDER_TTL: Duration = Duration::from_secs(30 * 60);
+pub const MAX_PROVIDERS: usize = 50;
+
+// node ids and box hashes live in the same 128-bit keyspace. longer box hashes are cut
+// down to fit, which only decides where their providers are kept
+pub type DhtKey = [u8; 16];
+
+pub fn key_for(hash: &BoxHash) -> DhtKey {
+    hash.as_bytes()[..16].try_into().unwrap()
+}
+
+fn distance(a: &DhtKey, b: &DhtKey) -> u128 {
+    u128::from_be_bytes(*a) ^ u128::from_be_bytes(*b)
+}
+
+pub struct RoutingTable {
+    pub own_id: NodeId,
+    buckets: Vec<Vec<Contact>>, // least recently seen first
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedTable {
+    node_id: NodeId,
+    contacts: Vec<Contact>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> RoutingTable {
+        RoutingTable {
+            own_id,
+            buckets: vec![Vec::new(); 128],
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<RoutingTable> {
+        let saved: SavedTable = rmp_serde::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut table = RoutingTable::new(saved.node_id);
+        for contact in saved.contacts {
+            table.insert(contact);
+        }
+
+        Ok(table)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let saved = SavedTable {
+            node_id: self.own_id,
+            contacts: self.contacts(),
+        };
+
+        fs::write(path, rmp_serde::to_vec(&saved).unwrap())
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        match distance(&self.own_id, id) {
+            0 => None,
+            d => Some(127 - d.leading_zeros() as usize),
+        }
+    }
+
+    // returns false if the bucket is full. like kademlia, we'd rather keep the nodes we
+    // already have, since nodes that have been up a while tend to stay up
+    pub fn insert(&mut self, contact: Contact) -> bool {
+        let bucket = match self.bucket_index(&contact.id) {
+            Some(i) => &mut self.buckets[i],
+            None => return false,
+        };
+
+        if let Some(pos) = bucket.iter().position(|c| c.id == contact.id) {
+            bucket.remove(pos);
+            bucket.push(contact);
+            true
+        } else if bucket.len() < K {
+            bucket.push(contact);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remove(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|c| c.addr != addr);
+        }
+    }
+
+    pub fn closest(&self, target: &DhtKey, n: usize) -> Vec<Contact> {
+        let mut contacts = self.contacts();
+        contacts.sort_by_key(|c| distance(&c.id, target));
+        contacts.truncate(n);
+        contacts
+    }
+
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.buckets.iter().flatten().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupKind {
+    Nodes,
+    Providers { announce: bool },
+}
+
+pub struct Lookup {
+    pub target: DhtKey,
+    pub kind: LookupKind,
+    candidates: Vec<Contact>, // sorted by distance to target
+    queried: HashSet<SocketAddr>,
+    pending: HashMap<SocketAddr, Instant>,
+    responded: Vec<Contact>,
+}
+
+impl Lookup {
+    fn new(target: DhtKey, kind: LookupKind) -> Lookup {
+        Lookup {
+            target,
+            kind,
+            candidates: Vec::new(),
+            queried: HashSet::new(),
+            pending: HashMap::new(),
+            responded: Vec::new(),
+        }
+    }
+
+    fn add_candidates(&mut self, contacts: impl IntoIterator<Item = Contact>) {
+        for contact in contacts {
+            if !self.candidates.iter().any(|c| c.addr == contact.addr) {
+                self.candidates.push(contact);
+            }
+        }
+
+        let target = self.target;
+        self.candidates.sort_by_key(|c| distance(&c.id, &target));
+    }
+
+    // the next nodes to ask: whichever of the K closest we haven't asked yet
+    fn next(&mut self) -> Vec<Contact> {
+        let free = ALPHA.saturating_sub(self.pending.len());
+        let next: Vec<Contact> = self
+            .candidates
+            .iter()
+            .take(K)
+            .filter(|c| !self.queried.contains(&c.addr))
+            .take(free)
+            .copied()
+            .collect();
+
+        for contact in &next {
+            self.queried.insert(contact.addr);
+            self.pending.insert(contact.addr, Instant::now());
+        }
+
+        next
+    }
+
+    fn is_done(&self) -> bool {
+        self.pending.is_empty()
+            && self
+                .candidates
+                .iter()
+                .take(K)
+                .all(|c| self.queried.contains(&c.addr))
+    }
+}
+
+pub struct Dht {
+    pub table: RoutingTable,
+    pub providers: HashMap<DhtKey, HashMap<SocketAddr, Instant>>,
+    lookups: HashMap<DhtKey, Lookup>,
+    packet_sender: Sender<LaminarPacket>,
+}
+
+impl Dht {
+    pub fn new(own_id: NodeId, sender: Sender<LaminarPacket>) -> Dht {
+        Dht {
+            table: RoutingTable::new(own_id),
+            providers: HashMap::new(),
+            lookups: HashMap::new(),
+            packet_sender: sender,
+        }
+    }
+
+    pub fn add_contact(&mut self, contact: Contact) {
+        self.table.insert(contact);
+    }
+
+    pub fn find_node(&mut self, target: NodeId) -> io::Result<()> {
+        self.start_lookup(target, LookupKind::Nodes)
+    }
+
+    // with `announce`, we also store ourselves as a provider on the closest nodes we find
+    pub fn find_providers(&mut self, hash: BoxHash, announce: bool) -> io::Result<()> {
+        self.start_lookup(key_for(&hash), LookupKind::Providers { announce })
+    }
+
+    fn start_lookup(&mut self, target: DhtKey, kind: LookupKind) -> io::Result<()> {
+        if self.lookups.contains_key(&target) {
+            return Ok(());
+        }
+
+        let mut lookup = Lookup::new(target, kind);
+        lookup.add_candidates(self.table.closest(&target, K));
+        self.lookups.insert(target, lookup);
+
+        self.step(target)
+    }
+
+    fn step(&mut self, target: DhtKey) -> io::Result<()> {
+        let lookup = match self.lookups.get_mut(&target) {
+            Some(lookup) => lookup,
+            None => return Ok(()),
+        };
+
+        let msg = match lookup.kind {
+            LookupKind::Nodes => DhtMessage::FindNode(target),
+            LookupKind::Providers { .. } => DhtMessage::GetProviders(target),
+        };
+
+        for contact in lookup.next() {
+            send(
+                &self.packet_sender,
+                Message::Dht(self.table.own_id, msg.clone()).to_packet(contact.addr),
+            )?;
+        }
+
+        if lookup.is_done() {
+            let mut lookup = self.lookups.remove(&target).unwrap();
+
+            if lookup.kind == (LookupKind::Providers { announce: true }) {
+                lookup.responded.sort_by_key(|c| distance(&c.id, &target));
+                for contact in lookup.responded.iter().take(K) {
+                    send(
+                        &self.packet_sender,
+                        Message::Dht(self.table.own_id, DhtMessage::AddProvider(target))
+                            .to_packet(contact.addr),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns providers we learned about, so the caller can go say hi to them
+    pub fn handle_msg(
+        &mut self,
+        from: SocketAddr,
+        from_id: NodeId,
+        message: DhtMessage,
+    ) -> io::Result<Vec<SocketAddr>> {
+        use DhtMessage::*;
+
+        let own_id = self.table.own_id;
+        self.table.insert(Contact {
+            id: from_id,
+            addr: from,
+        });
+
+        match message {
+            FindNode(target) => {
+                let nodes = self.closest_for(&target, from);
+                send(
+                    &self.packet_sender,
+                    Message::Dht(own_id, Nodes(target, nodes)).to_packet(from),
+                )?;
+            }
+            Nodes(target, nodes) => self.got_reply(from, from_id, target, nodes)?,
+            GetProviders(hash) => {
+                let providers = self
+                    .providers
+                    .get(&hash)
+                    .map(|p| {
+                        p.keys()
+                            .filter(|addr| **addr != from)
+                            .take(MAX_PROVIDERS)
+                            .copied()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let nodes = self.closest_for(&hash, from);
+
+                send(
+                    &self.packet_sender,
+                    Message::Dht(own_id, Providers(hash, providers, nodes)).to_packet(from),
+                )?;
+            }
+            Providers(hash, providers, nodes) => {
+                // ignore answers to questions we never asked
+                if !self
+                    .lookups
+                    .get(&hash)
+                    .map(|l| l.pending.contains_key(&from))
+                    .unwrap_or(false)
+                {
+                    return Ok(Vec::new());
+                }
+
+                self.got_reply(from, from_id, hash, nodes)?;
+                return Ok(providers.into_iter().take(MAX_PROVIDERS).collect());
+            }
+            AddProvider(hash) => {
+                let providers = self.providers.entry(hash).or_default();
+                if providers.len() < MAX_PROVIDERS || providers.contains_key(&from) {
+                    providers.insert(from, Instant::now());
+                }
+            }
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn closest_for(&self, target: &DhtKey, asker: SocketAddr) -> Vec<Contact> {
+        let mut nodes = self.table.closest(target, K + 1);
+        nodes.retain(|c| c.addr != asker);
+        nodes.truncate(K);
+        nodes
+    }
+
+    fn got_reply(
+        &mut self,
+        from: SocketAddr,
+        from_id: NodeId,
+        target: DhtKey,
+        nodes: Vec<Contact>,
+    ) -> io::Result<()> {
+        let own_id = self.table.own_id;
+
+        if let Some(lookup) = self.lookups.get_mut(&target) {
+            if lookup.pending.remove(&from).is_none() {
+                return Ok(());
+            }
+
+            lookup.responded.push(Contact {
+                id: from_id,
+                addr: from,
+            });
+            lookup.add_candidates(nodes.into_iter().filter(|c| c.id != own_id).take(K));
+        }
+
+        self.step(target)
+    }
+
+    // gives up on queries that never got answered (and the nodes that didn't answer them),
+    // moves stalled lookups along and expires old provider records
+    pub fn tick(&mut self) -> io::Result<()> {
+        let mut dead = Vec::new();
+        for lookup in self.lookups.values_mut() {
+            lookup.pending.retain(|addr, sent| {
+                let alive = sent.elapsed() < QUERY_TIMEOUT;
+                if !alive {
+                    dead.push(*addr);
+                }
+                alive
+            });
+        }
+
+        for addr in dead {
+            self.table.remove(addr);
+        }
+
+        for target in self.lookups.keys().copied().collect::<Vec<DhtKey>>() {
+            self.step(target)?;
+        }
+
+        for providers in self.providers.values_mut() {
+            providers.retain(|_, announced| announced.elapsed() < PROVIDER_TTL);
+        }
+        self.providers.retain(|_, p| !p.is_empty());
+
+        Ok(())
+    }
+}
+
+fn send(sender: &Sender<LaminarPacket>, packet: LaminarPacket) -> io::Result<()> {
+    sender.send(packet).map_err(io::Error::other)
+}
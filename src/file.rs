@@ -1,8 +1,13 @@
 // pub use crate::messages::*;
 pub use crate::*;
 
+use crate::hash::HashAlgorithm;
+use crate::hashing::{HashPool, Output, Owner, Task};
+use crate::picker::PickStrategy;
+use crate::secret::{derive_secret, BoxSecret};
 use memmap2::MmapMut;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 
 use std::fs::{self, File, OpenOptions};
 use std::io;
@@ -16,12 +21,83 @@ pub enum PieceState {
     Incomplete(usize, Vec<usize>),
 }
 
+#[derive(PartialEq, Debug)]
+pub enum ChunkResult {
+    Rejected,
+    Accepted,
+    Completed, // that chunk finished the piece and it verified
+    Corrupt,   // that chunk finished the piece, but it didn't verify and was cleared
+    BadChunk,  // that chunk didn't match its own hash, and was dropped
+}
+
+// how a box's files are cut up and hashed, from its metadata
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub piece_size: usize,
+    pub chunk_size: usize,
+    pub chunk_hashes: bool,
+    pub algorithm: HashAlgorithm,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            piece_size: PIECE_SIZE,
+            chunk_size: CHUNK_SIZE,
+            chunk_hashes: false,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+}
+
+impl Layout {
+    // chunks have to fit in a packet, and pieces have to fit a whole chunk
+    pub fn is_valid(&self) -> bool {
+        self.chunk_size > 0
+            && self.chunk_size <= max_chunk_size()
+            && self.piece_size >= self.chunk_size
+    }
+
+    pub fn chunk_count(&self, piece_len: usize) -> usize {
+        piece_len.div_ceil(self.chunk_size)
+    }
+
+    // with chunk hashes, a piece's hash is the merkle root of its chunks' hashes, so a piece
+    // can hand out its chunk hashes and have them checked against it
+    pub fn digest(&self, data: &[u8]) -> PieceHash {
+        if self.chunk_hashes {
+            merkle::root(self.algorithm, &self.chunk_digests(data))
+        } else {
+            self.algorithm.digest(data)
+        }
+    }
+
+    pub fn chunk_digests(&self, data: &[u8]) -> Vec<PieceHash> {
+        data.chunks(self.chunk_size)
+            .map(|chunk| self.algorithm.digest(chunk))
+            .collect()
+    }
+}
+
+impl CardboardMetadata {
+    pub fn layout(&self) -> Layout {
+        Layout {
+            piece_size: self.piece_size,
+            chunk_size: self.chunk_size,
+            chunk_hashes: self.chunk_hashes,
+            algorithm: self.hash_algorithm,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Piece {
-    pub hash: [u8; 16],
+    pub hash: Cell<Option<PieceHash>>, // None until a merkle proof vouches for one
     pub size: usize,
     pub state: RefCell<PieceState>,
     pub pointer: RefCell<*const u8>,
+    pub chunk_hashes: RefCell<Option<Vec<PieceHash>>>, // checked against `hash`, while downloading
+    pub proof: RefCell<Option<Vec<PieceHash>>>, // the one we were sent for `hash`, to pass on
 }
 
 #[derive(Debug)]
@@ -29,64 +105,30 @@ pub struct MappedFile {
     pub inner: MmapMut,
     pub size: usize,
     pub pieces: Vec<Piece>,
+    pub layout: Layout,
 }
 
 impl MappedFile {
-    pub fn from_whole_file(f: &File) -> io::Result<(FileHash, MappedFile)> {
-        let mut file_hasher = Blake2s16::new();
-        let mut piece_hasher = Blake2s16::new();
+    // a file we've just hashed on the pool (see PendingBox), so every piece is verified
+    pub fn from_hashed(f: &File, hashes: &[PieceHash], layout: Layout) -> io::Result<MappedFile> {
         let mmap = unsafe { MmapMut::map_mut(f)? };
-        let mut piece_pointers = Vec::new();
-
-        for piece in mmap.chunks(PIECE_SIZE) {
-            file_hasher.update(&piece);
-            piece_hasher.update(&piece);
+        let piece_iter = mmap.chunks(layout.piece_size);
 
-            piece_pointers.push(Piece {
-                hash: piece_hasher.finalize_reset().into(),
-                state: RefCell::new(PieceState::Downloaded),
-                size: piece.len(),
-                pointer: RefCell::new(piece.as_ptr()),
-            });
+        // it changed size while it was being hashed
+        if piece_iter.len() != hashes.len() {
+            return Err(io::ErrorKind::InvalidData.into());
         }
 
-        Ok((
-            file_hasher.finalize().into(),
-            MappedFile {
-                inner: mmap,
-                size: f.metadata()?.len() as usize,
-                pieces: piece_pointers,
-            },
-        ))
-    }
-
-    pub fn from_file_verified(f: &File, piece_hashes: &[[u8; 16]]) -> io::Result<MappedFile> {
-        let mmap = unsafe { MmapMut::map_mut(f)? };
-        let piece_iter = mmap.chunks(PIECE_SIZE);
-
-        if piece_hashes.len() != piece_iter.len() {
-            return Err(io::ErrorKind::InvalidInput.into());
-        }
-
-        let mut piece_hasher = Blake2s16::new();
         let mut piece_pointers = Vec::new();
 
         for (i, piece) in piece_iter.enumerate() {
-            piece_hasher.update(&piece);
-
-            let hash = piece_hasher.finalize_reset().into();
-
-            let chunk_amt = (piece.len() as f64 / CHUNK_SIZE as f64).ceil() as usize;
-
             piece_pointers.push(Piece {
-                hash,
-                state: RefCell::new(if piece_hashes[i] == hash {
-                    PieceState::Downloaded
-                } else {
-                    PieceState::Incomplete(chunk_amt, Vec::with_capacity(chunk_amt))
-                }),
+                hash: Cell::new(Some(hashes[i])),
+                state: RefCell::new(PieceState::Downloaded),
                 size: piece.len(),
                 pointer: RefCell::new(piece.as_ptr()),
+                chunk_hashes: RefCell::new(None),
+                proof: RefCell::new(None),
             });
         }
 
@@ -94,30 +136,43 @@ impl MappedFile {
             inner: mmap,
             size: f.metadata()?.len() as usize,
             pieces: piece_pointers,
+            layout,
         })
     }
 
-    pub fn from_file_empty(f: &File, piece_hashes: &[[u8; 16]]) -> io::Result<MappedFile> {
+    // every piece starts out needed. whatever's already on disk gets checked on the hash
+    // pool, and handed to mark_verified
+    pub fn from_file_unverified(
+        f: &File,
+        entry: &FileMetadata,
+        layout: Layout,
+    ) -> io::Result<MappedFile> {
         let mmap = unsafe { MmapMut::map_mut(f)? };
-        let piece_iter = mmap.chunks(PIECE_SIZE);
+        let piece_iter = mmap.chunks(layout.piece_size);
 
-        if piece_hashes.len() != piece_iter.len() {
-            return Err(io::ErrorKind::InvalidInput.into());
-        }
+        let hashes: Vec<Option<PieceHash>> = match entry.merkle_root {
+            Some(_) => vec![None; piece_iter.len()],
+            None if entry.pieces.len() == piece_iter.len() => {
+                entry.pieces.iter().copied().map(Some).collect()
+            }
+            None => return Err(io::ErrorKind::InvalidInput.into()),
+        };
 
         let mut piece_pointers = Vec::new();
 
         for (i, piece) in piece_iter.enumerate() {
-            let chunk_amt = (piece.len() as f64 / CHUNK_SIZE as f64).ceil() as usize;
+            let chunk_amt = layout.chunk_count(piece.len());
 
             piece_pointers.push(Piece {
-                hash: piece_hashes[i],
+                hash: Cell::new(hashes[i]),
                 state: RefCell::new(PieceState::Incomplete(
                     chunk_amt,
                     Vec::with_capacity(chunk_amt),
                 )),
                 size: piece.len(),
                 pointer: RefCell::new(piece.as_ptr()),
+                chunk_hashes: RefCell::new(None),
+                proof: RefCell::new(None),
             });
         }
 
@@ -125,11 +180,12 @@ impl MappedFile {
             inner: mmap,
             size: f.metadata()?.len() as usize,
             pieces: piece_pointers,
+            layout,
         })
     }
 
     pub fn has_piece(&self, piece: usize) -> bool {
-        if let Some(piece) = self.pieces.get(piece as usize) {
+        if let Some(piece) = self.pieces.get(piece) {
             *piece.state.borrow() == PieceState::Downloaded
         } else {
             false
@@ -137,7 +193,7 @@ impl MappedFile {
     }
 
     pub fn read_piece(&self, piece: usize) -> Option<&[u8]> {
-        if let Some(piece) = self.pieces.get(piece as usize) {
+        if let Some(piece) = self.pieces.get(piece) {
             if *piece.state.borrow() == PieceState::Downloaded {
                 return Some(unsafe {
                     std::slice::from_raw_parts(*piece.pointer.borrow(), piece.size)
@@ -148,26 +204,68 @@ impl MappedFile {
         None
     }
 
+    // the chunk hashes of whatever's in a piece right now, finished or not. after a piece
+    // fails to verify, this is still what was sent for it
+    pub fn piece_chunk_digests(&self, piece_index: usize) -> Option<Vec<PieceHash>> {
+        let piece = self.pieces.get(piece_index)?;
+        let data = unsafe { std::slice::from_raw_parts(*piece.pointer.borrow(), piece.size) };
+        Some(self.layout.chunk_digests(data))
+    }
+
+    // takes a piece's hash as we found it on disk. a piece that's still needed and matches
+    // is done. the hash is taken as is for pieces we had no hash for, once a merkle root
+    // has vouched for the whole file
+    pub fn mark_verified(&self, piece_index: usize, on_disk: PieceHash) -> bool {
+        let piece = match self.pieces.get(piece_index) {
+            Some(piece) => piece,
+            None => return false,
+        };
+
+        if piece.hash.get().is_none() {
+            piece.hash.set(Some(on_disk));
+        }
+
+        let mut piece_state = piece.state.borrow_mut();
+        if *piece_state == PieceState::Downloaded || piece.hash.get() != Some(on_disk) {
+            return false;
+        }
+
+        *piece_state = PieceState::Downloaded;
+        piece.chunk_hashes.replace(None);
+        true
+    }
+
     pub fn verify_piece(&self, piece: usize) -> bool {
         if let Some(data) = self.read_piece(piece) {
-            Blake2s16::digest(data).as_slice() == self.pieces[piece].hash
+            self.pieces[piece].hash.get() == Some(self.layout.digest(data))
         } else {
             false
         }
     }
 
-    pub fn write_chunk(&self, piece_index: usize, chunk_index: usize, data: &[u8]) -> bool {
-        if let Some(piece) = self.pieces.get(piece_index as usize) {
+    pub fn write_chunk(&self, piece_index: usize, chunk_index: usize, data: &[u8]) -> ChunkResult {
+        if let Some(piece) = self.pieces.get(piece_index) {
             let mut piece_state = piece.state.borrow_mut();
             if let PieceState::Incomplete(ref mut total, ref mut acquired) = *piece_state {
-                if chunk_index > *total {
-                    return false;
+                // anything else would land in the next piece, or off the end of the file
+                let expected_len = piece
+                    .size
+                    .saturating_sub(chunk_index * self.layout.chunk_size)
+                    .min(self.layout.chunk_size);
+                if chunk_index >= *total || data.len() != expected_len {
+                    return ChunkResult::Rejected;
                 };
 
                 if !acquired.contains(&chunk_index) {
+                    if let Some(hashes) = piece.chunk_hashes.borrow().as_ref() {
+                        if hashes.get(chunk_index) != Some(&self.layout.algorithm.digest(data)) {
+                            return ChunkResult::BadChunk;
+                        }
+                    }
+
                     unsafe {
                         (*piece.pointer.borrow_mut() as *mut u8)
-                            .add(chunk_index * CHUNK_SIZE)
+                            .add(chunk_index * self.layout.chunk_size)
                             .copy_from(data.as_ptr(), data.len());
                     }
 
@@ -177,25 +275,147 @@ impl MappedFile {
                 if *total == acquired.len() {
                     self.inner.flush().unwrap();
 
-                    let digest = Blake2s16::digest(unsafe {
+                    // held on to until its proof turns up
+                    let hash = match piece.hash.get() {
+                        Some(hash) => hash,
+                        None => return ChunkResult::Accepted,
+                    };
+
+                    let digest = self.layout.digest(unsafe {
                         std::slice::from_raw_parts(*piece.pointer.borrow(), piece.size)
-                    })
-                    .as_slice()
-                        == piece.hash;
+                    }) == hash;
 
                     if digest {
                         *piece_state = PieceState::Downloaded;
+                        piece.chunk_hashes.replace(None);
+                        return ChunkResult::Completed;
                     } else {
                         // if piece doesn't pass verification, clear it
                         acquired.clear();
+                        return ChunkResult::Corrupt;
                     }
                 }
 
-                return true;
+                return ChunkResult::Accepted;
             }
         }
 
-        false
+        ChunkResult::Rejected
+    }
+
+    // fills in a whole piece we got from somewhere other than a peer, like an older revision
+    pub fn write_piece(&self, piece_index: usize, data: &[u8]) -> bool {
+        let piece = match self.pieces.get(piece_index) {
+            Some(piece) => piece,
+            None => return false,
+        };
+
+        let mut piece_state = piece.state.borrow_mut();
+        if *piece_state == PieceState::Downloaded
+            || data.len() != piece.size
+            || piece.hash.get() != Some(self.layout.digest(data))
+        {
+            return false;
+        }
+
+        unsafe {
+            (*piece.pointer.borrow_mut() as *mut u8).copy_from(data.as_ptr(), data.len());
+        }
+        self.inner.flush().unwrap();
+        *piece_state = PieceState::Downloaded;
+
+        true
+    }
+
+    // sets a piece's hash once a merkle proof has vouched for it. if the piece is already
+    // all there, downloaded or left on disk from before, it's verified straight away
+    pub fn set_piece_hash(&self, piece_index: usize, hash: PieceHash) -> ChunkResult {
+        let piece = match self.pieces.get(piece_index) {
+            Some(piece) if piece.hash.get().is_none() => piece,
+            _ => return ChunkResult::Rejected,
+        };
+        piece.hash.set(Some(hash));
+
+        let mut piece_state = piece.state.borrow_mut();
+        let data = unsafe { std::slice::from_raw_parts(*piece.pointer.borrow(), piece.size) };
+        if self.layout.digest(data) == hash {
+            *piece_state = PieceState::Downloaded;
+            return ChunkResult::Completed;
+        }
+
+        match *piece_state {
+            PieceState::Incomplete(total, ref mut acquired) if acquired.len() == total => {
+                acquired.clear();
+                ChunkResult::Corrupt
+            }
+            _ => ChunkResult::Accepted,
+        }
+    }
+
+    // a downloaded piece's chunk hashes, for peers that want to check chunks as they come in
+    pub fn piece_chunk_hashes(&self, piece_index: usize) -> Option<Vec<PieceHash>> {
+        if !self.layout.chunk_hashes {
+            return None;
+        }
+
+        self.read_piece(piece_index)
+            .map(|data| self.layout.chunk_digests(data))
+    }
+
+    // takes a piece's chunk hashes if they add up to its hash, and drops any chunks we
+    // already have that don't match them. returns which ones were dropped, or None if
+    // the hashes were wrong
+    pub fn set_chunk_hashes(
+        &self,
+        piece_index: usize,
+        hashes: Vec<PieceHash>,
+    ) -> Option<Vec<usize>> {
+        let piece = self.pieces.get(piece_index)?;
+        let mut piece_state = piece.state.borrow_mut();
+
+        let (total, acquired) = match *piece_state {
+            PieceState::Incomplete(total, ref mut acquired) => (total, acquired),
+            PieceState::Downloaded => return Some(Vec::new()),
+        };
+        // can't check them yet, so we'll ask again once we can
+        let hash = match piece.hash.get() {
+            Some(hash) => hash,
+            None => return Some(Vec::new()),
+        };
+
+        if !self.layout.chunk_hashes
+            || hashes.len() != total
+            || merkle::root(self.layout.algorithm, &hashes) != hash
+        {
+            return None;
+        }
+
+        let data = unsafe { std::slice::from_raw_parts(*piece.pointer.borrow(), piece.size) };
+        let bad: Vec<usize> = acquired
+            .iter()
+            .copied()
+            .filter(|i| {
+                let chunk = data
+                    .chunks(self.layout.chunk_size)
+                    .nth(*i)
+                    .unwrap_or_default();
+                self.layout.algorithm.digest(chunk) != hashes[*i]
+            })
+            .collect();
+        acquired.retain(|i| !bad.contains(i));
+
+        piece.chunk_hashes.replace(Some(hashes));
+        Some(bad)
+    }
+
+    // whether we still want a piece's chunk hashes
+    pub fn wants_chunk_hashes(&self, piece_index: usize) -> bool {
+        self.layout.chunk_hashes
+            && self
+                .pieces
+                .get(piece_index)
+                .map(|p| p.hash.get().is_some() && p.chunk_hashes.borrow().is_none())
+                .unwrap_or(false)
     }
 
     pub fn needed_pieces(&self) -> Vec<usize> {
@@ -208,6 +428,28 @@ impl MappedFile {
 
         pieces
     }
+
+    // how many bytes from the start of the file are downloaded and verified, so readers can
+    // stream the file while the rest comes in
+    pub fn contiguous_bytes(&self) -> usize {
+        self.pieces
+            .iter()
+            .take_while(|p| *p.state.borrow() == PieceState::Downloaded)
+            .map(|p| p.size)
+            .sum()
+    }
+
+    // one bit per piece, set if we have it
+    pub fn bitfield(&self) -> Vec<u8> {
+        let mut bits = vec![0u8; self.pieces.len().div_ceil(8)];
+        for (i, piece) in self.pieces.iter().enumerate() {
+            if *piece.state.borrow() == PieceState::Downloaded {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        bits
+    }
 }
 
 // the torrent equivalent
@@ -217,24 +459,186 @@ pub struct CardboardBox {
     pub metadata: CardboardMetadata,
     pub base_path: PathBuf,
     pub files: Vec<MappedFile>,
+    pub strategy: PickStrategy,
+    pub sequential_files: HashSet<usize>, // files downloaded in order, ahead of everything else
+    pub secret: Option<BoxSecret>,        // set for private boxes
+    pub bytes_saved: u64,                 // copied from other local boxes instead of downloaded
+    pub verifying: Option<Verification>,  // while files already on disk are being checked
 }
 
-impl CardboardBox {
-    pub fn create(name: String, dir: impl AsRef<Path>) -> io::Result<CardboardBox> {
-        let mut entries = WalkDir::new(dir.as_ref())
+// a box whose files are still being hashed on the pool. it becomes a CardboardBox once
+// they all are
+#[derive(Debug)]
+pub struct PendingBox {
+    pub name: String,
+    pub base_path: PathBuf,
+    pub paths: Vec<PathBuf>,
+    pub sizes: Vec<usize>,
+    pub layout: Layout,
+    pub passphrase: Option<String>, // turned into a secret once we know the box's hash
+    merkle: bool,
+    file_hashes: Vec<Option<FileHash>>,
+    piece_hashes: Vec<Vec<Option<PieceHash>>>,
+    jobs: usize, // still out on the pool
+}
+
+impl PendingBox {
+    // `merkle` boxes list one merkle root per file instead of every piece hash, so their
+    // metadata stays small. `chunk_hashes` boxes hash their pieces with Layout::digest
+    pub fn new(
+        name: String,
+        dir: impl AsRef<Path>,
+        options: &BoxOptions,
+    ) -> io::Result<PendingBox> {
+        let layout = Layout {
+            piece_size: options.piece_size.unwrap_or(PIECE_SIZE),
+            chunk_size: options.chunk_size.unwrap_or(CHUNK_SIZE),
+            chunk_hashes: options.chunk_hashes,
+            algorithm: options.hash_algorithm,
+        };
+        if !layout.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "chunks have to be 1 to {} bytes, and no bigger than a piece",
+                    max_chunk_size()
+                ),
+            ));
+        }
+
+        let mut paths = WalkDir::new(dir.as_ref())
             .contents_first(true)
             .into_iter()
             .filter_entry(|e| !e.file_type().is_dir())
             .map(|e| e.map(|p| p.into_path()).map_err(|err| err.into()))
             .collect::<Result<Vec<PathBuf>, io::Error>>()?;
-        entries.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+        paths.sort_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+
+        let sizes = paths
+            .iter()
+            .map(|p| fs::metadata(p).map(|m| m.len() as usize))
+            .collect::<io::Result<Vec<usize>>>()?;
+        let piece_hashes: Vec<Vec<Option<PieceHash>>> = sizes
+            .iter()
+            .map(|size| vec![None; size.div_ceil(layout.piece_size)])
+            .collect();
+        let jobs = piece_hashes
+            .iter()
+            .map(|pieces| HashPool::job_count(pieces.len(), true))
+            .sum();
+
+        Ok(PendingBox {
+            name,
+            base_path: dir.as_ref().to_owned(),
+            file_hashes: vec![None; paths.len()],
+            paths,
+            sizes,
+            layout,
+            passphrase: options.passphrase.clone(),
+            merkle: options.merkle,
+            piece_hashes,
+            jobs,
+        })
+    }
+
+    pub fn submit(&self, pool: &HashPool, owner: Owner) {
+        for (i, path) in self.paths.iter().enumerate() {
+            let piece_count = self.piece_hashes[i].len();
+            pool.hash_file(owner, i, path.clone(), self.layout, piece_count, true);
+        }
+    }
+
+    // takes a job's hashes back from the pool. true once the last of them is in
+    pub fn add(&mut self, file_index: usize, task: Task, output: Output) -> io::Result<bool> {
+        self.jobs = self.jobs.saturating_sub(1);
+
+        match (task, output) {
+            (Task::File, Output::File(hash)) => {
+                if let Some(slot) = self.file_hashes.get_mut(file_index) {
+                    *slot = Some(hash);
+                }
+            }
+            (Task::Pieces(range), Output::Pieces(hashes)) => {
+                let slots = self
+                    .piece_hashes
+                    .get_mut(file_index)
+                    .and_then(|pieces| pieces.get_mut(range))
+                    .ok_or(io::ErrorKind::InvalidData)?;
+
+                // it shrank while it was being hashed
+                if slots.len() != hashes.len() {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                for (slot, hash) in slots.iter_mut().zip(hashes) {
+                    *slot = Some(hash);
+                }
+            }
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        }
 
-        let mut files: Vec<MappedFile> = Vec::with_capacity(entries.len());
-        let mut file_metadata: Vec<FileMetadata> = Vec::with_capacity(entries.len());
+        Ok(self.jobs == 0)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.jobs == 0
+    }
+
+    pub fn progress(&self) -> (usize, usize) {
+        let pieces = self.piece_hashes.iter().flatten();
+        (
+            pieces.clone().filter(|h| h.is_some()).count(),
+            pieces.count(),
+        )
+    }
+
+    pub fn get_download_state(&self) -> BoxState {
+        BoxState {
+            name: self.name.clone(),
+            box_hash: None,
+            strategy: PickStrategy::default(),
+            private: self.passphrase.is_some(),
+            bytes_saved: 0,
+            hashing: Some(self.progress()),
+            files: self
+                .paths
+                .iter()
+                .zip(&self.sizes)
+                .zip(&self.piece_hashes)
+                .map(|((path, size), pieces)| FileState {
+                    path: path
+                        .strip_prefix(&self.base_path)
+                        .unwrap_or(path)
+                        .to_owned(),
+                    pieces_downloaded: pieces.iter().filter(|h| h.is_some()).count(),
+                    total_pieces: pieces.len(),
+                    size: *size,
+                    contiguous_bytes: 0,
+                    sequential: false,
+                })
+                .collect(),
+        }
+    }
 
-        let mut hasher = Blake2s16::new();
+    // maps the files we hashed, and works out the box's hash
+    pub fn finish(self) -> io::Result<CardboardBox> {
+        let layout = self.layout;
+        let mut hasher = layout.algorithm.hasher();
+
+        let mut files: Vec<MappedFile> = Vec::with_capacity(self.paths.len());
+        let mut file_metadata: Vec<FileMetadata> = Vec::with_capacity(self.paths.len());
+
+        for ((entry, f_hash), pieces) in self
+            .paths
+            .into_iter()
+            .zip(self.file_hashes)
+            .zip(self.piece_hashes)
+        {
+            let f_hash = f_hash.ok_or(io::ErrorKind::InvalidData)?;
+            let pieces = pieces
+                .into_iter()
+                .collect::<Option<Vec<PieceHash>>>()
+                .ok_or(io::ErrorKind::InvalidData)?;
 
-        for entry in entries {
             let f = OpenOptions::new()
                 .write(true)
                 .read(true)
@@ -242,49 +646,92 @@ impl CardboardBox {
                 .truncate(false)
                 .open(&entry)?;
 
-            let (f_hash, mapped_file) = MappedFile::from_whole_file(&f)?;
+            let mapped_file = MappedFile::from_hashed(&f, &pieces, layout)?;
             hasher.update(f_hash);
 
-            let entry = if entry.starts_with(dir.as_ref()) {
-                entry.strip_prefix(dir.as_ref()).unwrap().to_owned()
+            let entry = if entry.starts_with(&self.base_path) {
+                entry.strip_prefix(&self.base_path).unwrap().to_owned()
             } else {
                 entry
             };
 
-            file_metadata.push(FileMetadata {
-                path: entry,
-                size: mapped_file.size,
-                pieces: mapped_file
-                    .pieces
-                    .iter()
-                    .map(|v| v.hash)
-                    .collect::<Vec<PieceHash>>(),
+            file_metadata.push(if self.merkle {
+                FileMetadata {
+                    path: entry,
+                    size: mapped_file.size,
+                    pieces: Vec::new(),
+                    merkle_root: Some(merkle::root(layout.algorithm, &pieces)),
+                }
+            } else {
+                FileMetadata {
+                    path: entry,
+                    size: mapped_file.size,
+                    pieces,
+                    merkle_root: None,
+                }
             });
 
             files.push(mapped_file);
         }
 
         let metadata = CardboardMetadata {
-            name,
+            name: self.name,
             files: file_metadata,
+            chunk_hashes: layout.chunk_hashes,
+            piece_size: layout.piece_size,
+            chunk_size: layout.chunk_size,
+            hash_algorithm: layout.algorithm,
         };
 
-        hasher.update(&rmp_serde::to_vec(&metadata).unwrap());
+        hasher.update(rmp_serde::to_vec(&metadata).unwrap());
+        let hash = hasher.finalize();
 
         Ok(CardboardBox {
-            hash: hasher.finalize().try_into().unwrap(),
+            hash,
             metadata,
             files,
-            base_path: dir.as_ref().to_owned(),
+            base_path: self.base_path,
+            strategy: PickStrategy::default(),
+            sequential_files: HashSet::new(),
+            secret: self.passphrase.as_deref().map(|p| derive_secret(p, &hash)),
+            bytes_saved: 0,
+            verifying: None,
         })
     }
+}
 
+// checking the files a box found already on disk, on the hash pool
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub files: Vec<usize>, // the ones that were there
+    generation: u64,       // jobs from an earlier add of the same box aren't ours
+    jobs: usize,           // still out on the pool
+    hashed: usize,
+    total: usize,
+    leaves: HashMap<usize, Vec<Option<PieceHash>>>, // merkle files' hashes, until they're all in
+}
+
+impl CardboardBox {
     pub fn from_metadata(
         dir: impl AsRef<Path>,
         hash: BoxHash,
         metadata: CardboardMetadata,
     ) -> io::Result<CardboardBox> {
         let mut files: Vec<MappedFile> = Vec::with_capacity(metadata.files.len());
+        let mut verification = Verification::default();
+
+        // it came from a peer, so it might ask for pieces we can't cut, or hand us hashes
+        // that aren't from the algorithm it claims
+        let layout = metadata.layout();
+        let hashes_match = metadata.files.iter().all(|f| {
+            f.pieces
+                .iter()
+                .chain(f.merkle_root.iter())
+                .all(|h| h.algorithm() == layout.algorithm)
+        });
+        if !layout.is_valid() || !hashes_match || hash.algorithm() != layout.algorithm {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
 
         dbg!(dir.as_ref());
         fs::create_dir_all(dir.as_ref())?;
@@ -297,27 +744,23 @@ impl CardboardBox {
                 fs::create_dir_all(p)?;
             }
 
-            if fpath.is_file() {
-                let f = OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .create(false)
-                    .truncate(false)
-                    .open(&fpath)?;
-                f.set_len(entry.size as u64)?;
-
-                files.push(MappedFile::from_file_verified(&f, &entry.pieces)?);
-            } else {
-                let f = OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(&fpath)?;
-                f.set_len(entry.size as u64)?;
-
-                files.push(MappedFile::from_file_empty(&f, &entry.pieces)?);
+            let existed = fpath.is_file();
+            let f = OpenOptions::new()
+                .write(true)
+                .read(true)
+                .create(!existed)
+                .truncate(!existed)
+                .open(&fpath)?;
+            f.set_len(entry.size as u64)?;
+
+            let mapped_file = MappedFile::from_file_unverified(&f, entry, layout)?;
+            // whatever's there is checked on the hash pool, see submit_verification
+            if existed && !mapped_file.pieces.is_empty() {
+                verification.files.push(files.len());
+                verification.jobs += HashPool::job_count(mapped_file.pieces.len(), false);
+                verification.total += mapped_file.pieces.len();
             }
+            files.push(mapped_file);
         }
 
         Ok(CardboardBox {
@@ -325,10 +768,105 @@ impl CardboardBox {
             base_path: dir.as_ref().to_owned(),
             metadata,
             files,
+            strategy: PickStrategy::default(),
+            sequential_files: HashSet::new(),
+            secret: None,
+            bytes_saved: 0,
+            verifying: Some(verification).filter(|v| v.jobs > 0),
         })
     }
 
+    pub fn submit_verification(&mut self, pool: &HashPool, generation: u64) {
+        let verification = match &mut self.verifying {
+            Some(verification) => verification,
+            None => return,
+        };
+        verification.generation = generation;
+
+        for &i in &verification.files {
+            pool.hash_file(
+                Owner::Box(self.hash, generation),
+                i,
+                self.base_path.join(&self.metadata.files[i].path),
+                self.files[i].layout,
+                self.files[i].pieces.len(),
+                false,
+            );
+        }
+    }
+
+    // takes on-disk piece hashes back from the pool. true once the last of them is in
+    pub fn add_verified(
+        &mut self,
+        generation: u64,
+        file_index: usize,
+        task: Task,
+        output: io::Result<Output>,
+    ) -> bool {
+        let verification = match self.verifying.as_mut() {
+            Some(verification) if verification.generation == generation => verification,
+            _ => return false,
+        };
+        verification.jobs = verification.jobs.saturating_sub(1);
+
+        let (range, hashes) = match (task, output) {
+            (Task::Pieces(range), Ok(Output::Pieces(hashes))) => (range, hashes),
+            // couldn't read them, so they'll just be downloaded
+            (Task::Pieces(range), _) => (range, Vec::new()),
+            (Task::File, _) => (0..0, Vec::new()),
+        };
+        verification.hashed += range.len();
+
+        if let (Some(file), Some(entry)) = (
+            self.files.get(file_index),
+            self.metadata.files.get(file_index),
+        ) {
+            match entry.merkle_root {
+                // all we can check a merkle file against is its root, so it's all or nothing
+                Some(root) => {
+                    let leaves = verification
+                        .leaves
+                        .entry(file_index)
+                        .or_insert_with(|| vec![None; file.pieces.len()]);
+                    for (i, hash) in range.zip(hashes) {
+                        if let Some(leaf) = leaves.get_mut(i) {
+                            *leaf = Some(hash);
+                        }
+                    }
+
+                    if leaves.iter().all(Option::is_some) {
+                        let leaves: Vec<PieceHash> = leaves.iter().flatten().copied().collect();
+                        verification.leaves.remove(&file_index);
+
+                        if merkle::root(self.metadata.hash_algorithm, &leaves) == root {
+                            for (i, hash) in leaves.into_iter().enumerate() {
+                                file.mark_verified(i, hash);
+                            }
+                        }
+                    }
+                }
+                None => {
+                    for (i, hash) in range.zip(hashes) {
+                        file.mark_verified(i, hash);
+                    }
+                }
+            }
+        }
+
+        if verification.jobs == 0 {
+            self.verifying = None;
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn needed_pieces(&self) -> Vec<(usize, Vec<usize>)> {
+        // nothing's needed until we know what's already on disk
+        if self.verifying.is_some() {
+            return Vec::new();
+        }
+
         let mut v = Vec::new();
         for (i, file) in self.files.iter().enumerate() {
             let pieces = file.needed_pieces();
@@ -340,10 +878,227 @@ impl CardboardBox {
         v
     }
 
+    pub fn needed_piece_keys(&self) -> Vec<PieceKey> {
+        self.needed_pieces()
+            .into_iter()
+            .flat_map(|(file_index, pieces)| pieces.into_iter().map(move |p| (file_index, p)))
+            .collect()
+    }
+
+    pub fn order_needed(&self, needed: &mut [PieceKey], availability: &HashMap<PieceKey, usize>) {
+        self.strategy.picker().order(needed, availability);
+
+        // stable, so everything that isn't sequential keeps the picker's order
+        needed.sort_by_key(|(file_index, piece_index)| {
+            if self.sequential_files.contains(file_index) {
+                (0, *file_index, *piece_index)
+            } else {
+                (1, 0, 0)
+            }
+        });
+    }
+
+    pub fn set_sequential(&mut self, path: &Path, sequential: bool) -> bool {
+        match self.metadata.files.iter().position(|f| f.path == path) {
+            Some(i) => {
+                if sequential {
+                    self.sequential_files.insert(i);
+                } else {
+                    self.sequential_files.remove(&i);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn bitfield(&self) -> Vec<Vec<u8>> {
+        self.files.iter().map(|f| f.bitfield()).collect()
+    }
+
+    pub fn uses_merkle(&self) -> bool {
+        self.metadata.files.iter().any(|f| f.merkle_root.is_some())
+    }
+
+    // what a peer has to understand to make sense of our metadata
+    pub fn required_features(&self) -> Features {
+        let mut features = Features::default();
+        if self.uses_merkle() {
+            features = features | Features::MERKLE;
+        }
+        if self.metadata.chunk_hashes {
+            features = features | Features::CHUNK_HASHES;
+        }
+        if self.metadata.piece_size != PIECE_SIZE || self.metadata.chunk_size != CHUNK_SIZE {
+            features = features | Features::LAYOUT;
+        }
+        if self.metadata.hash_algorithm != HashAlgorithm::Blake2s128 {
+            features = features | Features::LONG_HASHES;
+        }
+
+        features
+    }
+
+    // a piece's hash and the proof tying it to its file's merkle root. we can only make
+    // one once we know every piece hash in the file, so until then we pass on the one we
+    // were sent
+    pub fn piece_proof(
+        &self,
+        (file_index, piece_index): PieceKey,
+    ) -> Option<(PieceHash, Vec<PieceHash>)> {
+        self.metadata.files.get(file_index)?.merkle_root?;
+        let file = self.files.get(file_index)?;
+        let piece = file.pieces.get(piece_index)?;
+
+        let leaves = file
+            .pieces
+            .iter()
+            .map(|p| p.hash.get())
+            .collect::<Option<Vec<PieceHash>>>();
+
+        match leaves {
+            Some(leaves) => Some((
+                leaves[piece_index],
+                merkle::proof(self.metadata.hash_algorithm, &leaves, piece_index),
+            )),
+            None => Some((piece.hash.get()?, piece.proof.borrow().clone()?)),
+        }
+    }
+
+    // checks a proof we were sent, and if it holds, hands the hash to the piece. None if it
+    // doesn't hold (or isn't for a merkle file at all)
+    pub fn add_proof(
+        &self,
+        (file_index, piece_index): PieceKey,
+        hash: PieceHash,
+        proof: &[PieceHash],
+    ) -> Option<ChunkResult> {
+        let root = self.metadata.files.get(file_index)?.merkle_root?;
+        let file = self.files.get(file_index)?;
+
+        let algorithm = self.metadata.hash_algorithm;
+        if merkle::verify(
+            algorithm,
+            &root,
+            &hash,
+            piece_index,
+            file.pieces.len(),
+            proof,
+        ) {
+            file.pieces[piece_index].proof.replace(Some(proof.to_vec()));
+            Some(file.set_piece_hash(piece_index, hash))
+        } else {
+            None
+        }
+    }
+
+    pub fn piece_hash(&self, (file_index, piece_index): PieceKey) -> Option<PieceHash> {
+        self.files
+            .get(file_index)
+            .and_then(|f| f.pieces.get(piece_index))
+            .and_then(|p| p.hash.get())
+    }
+
+    pub fn verified_piece_keys(&self) -> Vec<PieceKey> {
+        self.files
+            .iter()
+            .enumerate()
+            .flat_map(|(file_index, file)| {
+                (0..file.pieces.len())
+                    .filter(|p| file.has_piece(*p))
+                    .map(move |p| (file_index, p))
+            })
+            .collect()
+    }
+
+    // where we have the verified pieces `next` wants that moved to another file or offset.
+    // ones still in the same place get picked up when the new box is verified. a merkle
+    // `next` doesn't say what its pieces are up front, so nothing moved can be matched
+    pub fn pieces_for(&self, next: &CardboardMetadata) -> HashMap<PieceHash, PieceKey> {
+        let mut have: HashMap<PieceHash, PieceKey> = HashMap::new();
+        for (file_index, file) in self.files.iter().enumerate() {
+            for (piece_index, piece) in file.pieces.iter().enumerate() {
+                if let Some(hash) = piece.hash.get().filter(|_| file.has_piece(piece_index)) {
+                    have.entry(hash).or_insert((file_index, piece_index));
+                }
+            }
+        }
+
+        // `next` gets opened over our files while we're still mapped, so anything past where
+        // it cuts a file short can't be read any more
+        have.retain(|_, (file_index, piece_index)| {
+            let file = &self.files[*file_index];
+            let end = *piece_index * file.layout.piece_size + file.pieces[*piece_index].size;
+            next.files
+                .iter()
+                .find(|f| f.path == self.metadata.files[*file_index].path)
+                .map(|f| end <= f.size)
+                .unwrap_or(true)
+        });
+
+        let mut pieces = HashMap::new();
+        for entry in &next.files {
+            let same_file = self
+                .metadata
+                .files
+                .iter()
+                .position(|f| f.path == entry.path);
+
+            for (piece_index, hash) in entry.pieces.iter().enumerate() {
+                let in_place = same_file
+                    .map(|i| {
+                        self.metadata.files[i].pieces.get(piece_index) == Some(hash)
+                            && self.files[i].has_piece(piece_index)
+                    })
+                    .unwrap_or(false);
+                if in_place || pieces.contains_key(hash) {
+                    continue;
+                }
+
+                if let Some(key) = have.get(hash) {
+                    pieces.insert(*hash, *key);
+                }
+            }
+        }
+
+        pieces
+    }
+
+    // fills in whatever needed pieces `previous` has at the `moved` locations. returns how
+    // many. we share files with it, so an earlier copy can land on one that's yet to be
+    // read; write_piece checks the hash, so that one just gets downloaded instead
+    pub fn reuse_pieces(
+        &self,
+        previous: &CardboardBox,
+        moved: &HashMap<PieceHash, PieceKey>,
+    ) -> usize {
+        let mut reused = 0;
+        for file in &self.files {
+            for piece_index in file.needed_pieces() {
+                let data = file.pieces[piece_index]
+                    .hash
+                    .get()
+                    .and_then(|h| moved.get(&h))
+                    .and_then(|(f, p)| previous.files.get(*f)?.read_piece(*p));
+                if let Some(data) = data {
+                    if file.write_piece(piece_index, data) {
+                        reused += 1;
+                    }
+                }
+            }
+        }
+
+        reused
+    }
+
     pub fn get_download_state(&self) -> BoxState {
         BoxState {
             name: self.metadata.name.clone(),
-            box_hash: self.hash,
+            box_hash: Some(self.hash),
+            strategy: self.strategy,
+            private: self.secret.is_some(),
+            bytes_saved: self.bytes_saved,
+            hashing: self.verifying.as_ref().map(|v| (v.hashed, v.total)),
             files: self
                 .files
                 .iter()
@@ -356,6 +1111,9 @@ impl CardboardBox {
                         .filter(|v| *v.state.borrow() == PieceState::Downloaded)
                         .count(),
                     total_pieces: file.pieces.len(),
+                    size: file.size,
+                    contiguous_bytes: file.contiguous_bytes(),
+                    sequential: self.sequential_files.contains(&i),
                 })
                 .collect(),
         }
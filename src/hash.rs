@@ -0,0 +1,241 @@
+use crate::Blake2s16;
+
+use blake2::{Blake2s256, Digest};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAX_HASH_LEN: usize = 32;
+
+// a box, file or piece hash of whichever length its box's algorithm makes. 16 byte ones
+// serialize exactly like the [u8; 16] they used to be, so opencomputers peers can still
+// read them
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Hash {
+    len: u8,
+    bytes: [u8; MAX_HASH_LEN],
+}
+
+impl Hash {
+    pub fn from_slice(bytes: &[u8]) -> Option<Hash> {
+        HashAlgorithm::for_len(bytes.len())?;
+
+        let mut hash = Hash {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_HASH_LEN],
+        };
+        hash.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(hash)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::for_len(self.len as usize).unwrap()
+    }
+}
+
+// what [u8; 16]::default() was
+impl Default for Hash {
+    fn default() -> Hash {
+        HashAlgorithm::default().zero()
+    }
+}
+
+impl From<[u8; 16]> for Hash {
+    fn from(bytes: [u8; 16]) -> Hash {
+        Hash::from_slice(&bytes).unwrap()
+    }
+}
+
+impl AsRef<[u8]> for Hash {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.as_bytes()))
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.len as usize)?;
+        for byte in self.as_bytes() {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+struct HashVisitor;
+
+impl<'de> Visitor<'de> for HashVisitor {
+    type Value = Hash;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a 16 or 32 byte hash")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Hash, A::Error> {
+        let mut bytes = Vec::with_capacity(MAX_HASH_LEN);
+        while let Some(byte) = seq.next_element::<u8>()? {
+            if bytes.len() == MAX_HASH_LEN {
+                return Err(de::Error::invalid_length(bytes.len() + 1, &self));
+            }
+            bytes.push(byte);
+        }
+
+        Hash::from_slice(&bytes).ok_or_else(|| de::Error::invalid_length(bytes.len(), &self))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Hash, E> {
+        Hash::from_slice(bytes).ok_or_else(|| E::invalid_length(bytes.len(), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+        deserializer.deserialize_seq(HashVisitor)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake2s128, // short, but all opencomputers can do
+    Blake2s256, // for boxes shared with peers we don't trust not to look for collisions
+}
+
+impl HashAlgorithm {
+    fn for_len(len: usize) -> Option<HashAlgorithm> {
+        match len {
+            16 => Some(HashAlgorithm::Blake2s128),
+            32 => Some(HashAlgorithm::Blake2s256),
+            _ => None,
+        }
+    }
+
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Blake2s128 => 16,
+            HashAlgorithm::Blake2s256 => 32,
+        }
+    }
+
+    pub fn zero(self) -> Hash {
+        Hash {
+            len: self.digest_len() as u8,
+            bytes: [0; MAX_HASH_LEN],
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake2s128 => Hasher::Blake2s128(Blake2s16::new()),
+            HashAlgorithm::Blake2s256 => Hasher::Blake2s256(Blake2s256::new()),
+        }
+    }
+
+    pub fn digest(self, data: impl AsRef<[u8]>) -> Hash {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s {
+            "blake2s128" | "blake2s-128" => Ok(HashAlgorithm::Blake2s128),
+            "blake2s256" | "blake2s-256" => Ok(HashAlgorithm::Blake2s256),
+            _ => Err(format!(
+                "unknown hash {}, expected blake2s128 or blake2s256",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Blake2s128 => write!(f, "blake2s128"),
+            HashAlgorithm::Blake2s256 => write!(f, "blake2s256"),
+        }
+    }
+}
+
+pub enum Hasher {
+    Blake2s128(Blake2s16),
+    Blake2s256(Blake2s256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Hasher::Blake2s128(hasher) => hasher.update(data),
+            Hasher::Blake2s256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        match self {
+            Hasher::Blake2s128(hasher) => Hash::from_slice(&hasher.finalize()).unwrap(),
+            Hasher::Blake2s256(hasher) => Hash::from_slice(&hasher.finalize()).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_hash_is_a_plain_array() {
+        let bytes: [u8; 16] = std::array::from_fn(|i| i as u8 * 17);
+        let hash = Hash::from(bytes);
+        let encoded = rmp_serde::to_vec(&hash).unwrap();
+
+        assert_eq!(encoded, rmp_serde::to_vec(&bytes).unwrap());
+        assert_eq!(rmp_serde::from_slice::<Hash>(&encoded).unwrap(), hash);
+        assert_eq!(rmp_serde::from_slice::<[u8; 16]>(&encoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn long_hash_round_trips() {
+        let hash = HashAlgorithm::Blake2s256.digest(b"nyah");
+        let decoded: Hash = rmp_serde::from_slice(&rmp_serde::to_vec(&hash).unwrap()).unwrap();
+
+        assert_eq!(decoded, hash);
+        assert_eq!(decoded.algorithm(), HashAlgorithm::Blake2s256);
+        assert_eq!(decoded.as_bytes().len(), 32);
+    }
+
+    #[test]
+    fn reads_binary_too() {
+        let hash = HashAlgorithm::Blake2s128.digest(b"nyah");
+        let encoded = rmp_serde::to_vec(&serde_bytes::Bytes::new(hash.as_bytes())).unwrap();
+
+        assert_eq!(rmp_serde::from_slice::<Hash>(&encoded).unwrap(), hash);
+    }
+
+    #[test]
+    fn other_lengths_are_rejected() {
+        for len in [0, 1, 15, 17, 31, 33, 64] {
+            let bytes = vec![7u8; len];
+            assert!(Hash::from_slice(&bytes).is_none());
+            assert!(rmp_serde::from_slice::<Hash>(&rmp_serde::to_vec(&bytes).unwrap()).is_err());
+            let binary = rmp_serde::to_vec(&serde_bytes::Bytes::new(&bytes)).unwrap();
+            assert!(rmp_serde::from_slice::<Hash>(&binary).is_err());
+        }
+    }
+}
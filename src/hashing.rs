@@ -0,0 +1,147 @@
+use crate::file::Layout;
+use crate::*;
+
+use crossbeam_channel::{Receiver, Sender};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::thread;
+
+// how many pieces one job hashes, so a big file gets spread across the workers
+pub const PIECES_PER_JOB: usize = 256;
+
+pub type CreationId = u64;
+
+// who a job's result goes back to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    Creation(CreationId), // a box being created
+    Box(BoxHash, u64),    // a box checking what's already on disk, and which time
+}
+
+#[derive(Clone, Debug)]
+pub enum Task {
+    File,                 // the whole-file hash, which has to be done in one go
+    Pieces(Range<usize>), // piece hashes, which can be split up
+}
+
+pub struct Job {
+    pub owner: Owner,
+    pub file_index: usize,
+    pub path: PathBuf,
+    pub layout: Layout,
+    pub task: Task,
+}
+
+pub enum Output {
+    File(FileHash),
+    Pieces(Vec<PieceHash>),
+}
+
+pub struct JobResult {
+    pub owner: Owner,
+    pub file_index: usize,
+    pub task: Task,
+    pub output: io::Result<Output>,
+}
+
+// hashes files on a few worker threads, so creating or checking a big box doesn't stop
+// the daemon from answering peers
+pub struct HashPool {
+    jobs: Sender<Job>,
+    results: Receiver<JobResult>,
+}
+
+impl HashPool {
+    pub fn new(workers: usize) -> HashPool {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded::<Job>();
+        let (result_sender, results) = crossbeam_channel::unbounded();
+
+        for _ in 0..workers.max(1) {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+
+            thread::spawn(move || {
+                for job in job_receiver {
+                    let output = run(&job);
+                    let result = JobResult {
+                        owner: job.owner,
+                        file_index: job.file_index,
+                        task: job.task,
+                        output,
+                    };
+
+                    if result_sender.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        HashPool { jobs, results }
+    }
+
+    // queues up hashing `piece_count` pieces of a file, and the whole file if `whole`
+    pub fn hash_file(
+        &self,
+        owner: Owner,
+        file_index: usize,
+        path: PathBuf,
+        layout: Layout,
+        piece_count: usize,
+        whole: bool,
+    ) {
+        let mut tasks: Vec<Task> = (0..piece_count)
+            .step_by(PIECES_PER_JOB)
+            .map(|start| Task::Pieces(start..(start + PIECES_PER_JOB).min(piece_count)))
+            .collect();
+        if whole {
+            tasks.push(Task::File);
+        }
+
+        for task in tasks {
+            // the workers only stop once we're gone
+            let _ = self.jobs.send(Job {
+                owner,
+                file_index,
+                path: path.clone(),
+                layout,
+                task,
+            });
+        }
+    }
+
+    // how many jobs hash_file queues
+    pub fn job_count(piece_count: usize, whole: bool) -> usize {
+        piece_count.div_ceil(PIECES_PER_JOB) + whole as usize
+    }
+
+    pub fn results(&self) -> impl Iterator<Item = JobResult> + '_ {
+        self.results.try_iter()
+    }
+}
+
+fn run(job: &Job) -> io::Result<Output> {
+    let f = File::open(&job.path)?;
+    let mmap = unsafe { Mmap::map(&f)? };
+    let pieces = mmap.chunks(job.layout.piece_size);
+
+    Ok(match &job.task {
+        Task::File => {
+            let mut hasher = job.layout.algorithm.hasher();
+            for piece in pieces {
+                hasher.update(piece);
+            }
+            Output::File(hasher.finalize())
+        }
+        Task::Pieces(range) => Output::Pieces(
+            pieces
+                .skip(range.start)
+                .take(range.len())
+                .map(|piece| job.layout.digest(piece))
+                .collect(),
+        ),
+    })
+}
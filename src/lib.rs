@@ -1,16 +1,37 @@
 // pub mod messages;
 // pub mod state;
+pub mod acl;
+pub mod channel;
+pub mod crypto;
+pub mod dht;
 pub mod file;
+pub mod hash;
+pub mod hashing;
+pub mod merkle;
 pub use types::*;
+pub mod peer;
+pub mod picker;
+pub mod secret;
 pub mod state;
+pub mod tracker;
 pub mod types;
 
 use blake2::{digest::consts::U16, Blake2s, Digest};
 
+// defaults, sized for opencomputers. boxes can pick their own
 pub const PIECE_SIZE: usize = 48_000;
 pub const CHUNK_SIZE: usize = 256;
+// room a sealed Upload needs around its chunk, for the ids, msgpack framing and tag
+pub const UPLOAD_OVERHEAD: usize = 256;
 
-pub type PieceHash = [u8; 16];
-pub type BoxHash = [u8; 16];
-pub type FileHash = [u8; 16];
+pub type PieceHash = hash::Hash;
+pub type BoxHash = hash::Hash;
+pub type FileHash = hash::Hash;
+pub type NodeId = [u8; 16];
+pub type PieceKey = (usize, usize); // (file index, piece index)
 pub type Blake2s16 = Blake2s<U16>;
+
+// a chunk has to go out in one laminar packet, however big laminar lets those get
+pub fn max_chunk_size() -> usize {
+    laminar::Config::default().max_packet_size - UPLOAD_OVERHEAD
+}
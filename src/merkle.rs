@@ -0,0 +1,189 @@
+use crate::hash::HashAlgorithm;
+use crate::*;
+
+// a tree over a file's piece hashes. a lone node at the end of a level is carried up as is,
+// so a proof only has siblings for the levels where there was one
+
+fn parent(algorithm: HashAlgorithm, left: &PieceHash, right: &PieceHash) -> PieceHash {
+    let mut hasher = algorithm.hasher();
+    // so an inner node can never pass for a piece hash
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+fn levels(algorithm: HashAlgorithm, leaves: &[PieceHash]) -> Vec<Vec<PieceHash>> {
+    let mut levels = vec![leaves.to_vec()];
+
+    while levels.last().unwrap().len() > 1 {
+        let next = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => parent(algorithm, left, right),
+                [lone] => *lone,
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+
+    levels
+}
+
+pub fn root(algorithm: HashAlgorithm, leaves: &[PieceHash]) -> PieceHash {
+    levels(algorithm, leaves)
+        .last()
+        .and_then(|level| level.first())
+        .copied()
+        .unwrap_or(algorithm.zero())
+}
+
+// sibling hashes from the leaf up
+pub fn proof(algorithm: HashAlgorithm, leaves: &[PieceHash], mut index: usize) -> Vec<PieceHash> {
+    let levels = levels(algorithm, leaves);
+    let mut proof = Vec::new();
+
+    for level in &levels[..levels.len() - 1] {
+        if let Some(sibling) = level.get(index ^ 1) {
+            proof.push(*sibling);
+        }
+        index /= 2;
+    }
+
+    proof
+}
+
+pub fn verify(
+    algorithm: HashAlgorithm,
+    root: &PieceHash,
+    leaf: &PieceHash,
+    mut index: usize,
+    leaf_count: usize,
+    proof: &[PieceHash],
+) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+
+    let mut hash = *leaf;
+    let mut width = leaf_count;
+    let mut siblings = proof.iter();
+
+    while width > 1 {
+        if (index ^ 1) < width {
+            let sibling = match siblings.next() {
+                Some(sibling) => sibling,
+                None => return false,
+            };
+
+            hash = if index & 1 == 0 {
+                parent(algorithm, &hash, sibling)
+            } else {
+                parent(algorithm, sibling, &hash)
+            };
+        }
+
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+
+    siblings.next().is_none() && hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<PieceHash> {
+        (0..count)
+            .map(|i| HashAlgorithm::Blake2s128.digest(i.to_le_bytes()))
+            .collect()
+    }
+
+    fn proves_every_leaf(count: usize) -> bool {
+        let algorithm = HashAlgorithm::Blake2s128;
+        let leaves = leaves(count);
+        let root = root(algorithm, &leaves);
+
+        (0..count).all(|i| {
+            let proof = proof(algorithm, &leaves, i);
+            verify(algorithm, &root, &leaves[i], i, count, &proof)
+        })
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        let algorithm = HashAlgorithm::Blake2s128;
+        let leaves = leaves(1);
+
+        assert_eq!(root(algorithm, &leaves), leaves[0]);
+        assert!(proof(algorithm, &leaves, 0).is_empty());
+        assert!(verify(algorithm, &leaves[0], &leaves[0], 0, 1, &[]));
+    }
+
+    #[test]
+    fn no_leaves() {
+        let algorithm = HashAlgorithm::Blake2s128;
+        assert_eq!(root(algorithm, &[]), algorithm.zero());
+    }
+
+    #[test]
+    fn even_counts() {
+        assert!(proves_every_leaf(2));
+        assert!(proves_every_leaf(4));
+        assert!(proves_every_leaf(16));
+    }
+
+    #[test]
+    fn odd_counts() {
+        assert!(proves_every_leaf(3));
+        assert!(proves_every_leaf(5));
+        assert!(proves_every_leaf(7));
+        assert!(proves_every_leaf(13));
+    }
+
+    #[test]
+    fn lone_node_is_carried_up() {
+        let algorithm = HashAlgorithm::Blake2s128;
+        let leaves = leaves(3);
+        let expected = parent(
+            algorithm,
+            &parent(algorithm, &leaves[0], &leaves[1]),
+            &leaves[2],
+        );
+
+        assert_eq!(root(algorithm, &leaves), expected);
+        assert_eq!(
+            proof(algorithm, &leaves, 2),
+            vec![parent(algorithm, &leaves[0], &leaves[1])]
+        );
+    }
+
+    #[test]
+    fn wrong_index() {
+        let algorithm = HashAlgorithm::Blake2s128;
+        let leaves = leaves(5);
+        let root = root(algorithm, &leaves);
+        let proof = proof(algorithm, &leaves, 1);
+
+        assert!(!verify(algorithm, &root, &leaves[1], 0, 5, &proof));
+        assert!(!verify(algorithm, &root, &leaves[1], 3, 5, &proof));
+        assert!(!verify(algorithm, &root, &leaves[1], 5, 5, &proof));
+    }
+
+    #[test]
+    fn wrong_leaf_or_count() {
+        let algorithm = HashAlgorithm::Blake2s128;
+        let leaves = leaves(6);
+        let root = root(algorithm, &leaves);
+        let proof = proof(algorithm, &leaves, 4);
+
+        assert!(!verify(algorithm, &root, &leaves[3], 4, 6, &proof));
+        // in a five leaf tree the last one has no sibling at the bottom
+        assert!(!verify(algorithm, &root, &leaves[4], 4, 5, &proof));
+        assert!(!verify(algorithm, &root, &leaves[4], 4, 6, &proof[1..]));
+    }
+}
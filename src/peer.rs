@@ -0,0 +1,270 @@
+use crate::secret::Nonce;
+use crate::*;
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// penalties add up per address (several nodes can share an ip behind a nat), and past
+// BAN_THRESHOLD we stop talking to that address for BAN_DURATION
+pub const BAN_THRESHOLD: u32 = 100;
+pub const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+pub const CORRUPT_PIECE_PENALTY: u32 = 25;
+// less than a whole piece, but a peer sending bad chunks is still caught quickly
+pub const CORRUPT_CHUNK_PENALTY: u32 = 10;
+pub const MALFORMED_PENALTY: u32 = 5;
+// how long a private box challenge gets answered in before we ask again
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+// after a wrong answer, how long until they get another go. slows down guessing without
+// banning somebody for a typo
+pub const BAD_PROOF_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub node_id: Option<NodeId>,
+    pub version: u32, // 0 until they say hello
+    pub features: Features,
+    pub last_seen: Instant,
+    pub rtt: Option<Duration>,
+    pub state: ConnectionState,
+    pub boxes: HashSet<BoxHash>, // boxes this peer is seeding or looking for
+    pub pieces: HashMap<BoxHash, HashSet<PieceKey>>, // pieces we know they have
+    pub interested: HashMap<BoxHash, Instant>, // last time they asked us for a piece
+    pub uploaded_to_us: HashMap<BoxHash, u64>, // bytes since the last rechoke
+    pub unchoked: HashSet<BoxHash>, // boxes we'll upload to them from
+    pub choking_us: HashSet<BoxHash>, // boxes they won't upload to us from
+    pub last_pex_request: HashMap<Option<BoxHash>, Instant>, // requests they sent us
+    pub pex_pending: HashSet<Option<BoxHash>>, // requests we sent them
+    pub authorized: HashSet<BoxHash>, // private boxes they've proven they can open
+    pub challenges: HashMap<BoxHash, (Nonce, Instant)>, // challenges we're waiting on answers to
+    pub bad_proofs: HashMap<BoxHash, Instant>, // when they last answered one wrong
+    pinged_at: Option<Instant>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Peer {
+        Peer {
+            addr,
+            node_id: None,
+            version: 0,
+            features: Features::NONE,
+            last_seen: Instant::now(),
+            rtt: None,
+            state: ConnectionState::Connected,
+            boxes: HashSet::new(),
+            pieces: HashMap::new(),
+            interested: HashMap::new(),
+            uploaded_to_us: HashMap::new(),
+            unchoked: HashSet::new(),
+            choking_us: HashSet::new(),
+            last_pex_request: HashMap::new(),
+            pex_pending: HashSet::new(),
+            authorized: HashSet::new(),
+            challenges: HashMap::new(),
+            bad_proofs: HashMap::new(),
+            pinged_at: None,
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+        self.state = ConnectionState::Connected;
+    }
+
+    pub fn pinged(&mut self) {
+        self.pinged_at = Some(Instant::now());
+    }
+
+    // called when a peer answers our SearchingForPeers
+    pub fn ponged(&mut self) {
+        if let Some(pinged_at) = self.pinged_at.take() {
+            self.rtt = Some(pinged_at.elapsed());
+        }
+
+        self.seen();
+    }
+
+    pub fn has_piece(&self, id: &BoxHash, key: &PieceKey) -> bool {
+        self.pieces
+            .get(id)
+            .map(|p| p.contains(key))
+            .unwrap_or(false)
+    }
+
+    // whether we can ask them for this piece right now
+    pub fn can_send(&self, id: &BoxHash, key: &PieceKey) -> bool {
+        self.is_connected() && self.has_piece(id, key) && !self.choking_us.contains(id)
+    }
+
+    pub fn is_interested(&self, id: &BoxHash, timeout: Duration) -> bool {
+        self.interested
+            .get(id)
+            .map(|t| t.elapsed() < timeout)
+            .unwrap_or(false)
+    }
+
+    pub fn hello(&mut self, hello: &Hello) {
+        // legacy hellos don't come with a node id
+        if hello.version != 0 {
+            self.node_id = Some(hello.node_id);
+        }
+        self.version = hello.version;
+        // only keep what we both understand
+        self.features = Features(hello.features.0 & Features::supported().0);
+    }
+
+    pub fn supports(&self, feature: Features) -> bool {
+        self.features.contains(feature)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == ConnectionState::Connected
+    }
+
+    pub fn is_expired(&self, timeout: Duration) -> bool {
+        self.last_seen.elapsed() > timeout
+    }
+
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            addr: self.addr,
+            last_seen_secs: self.last_seen.elapsed().as_secs(),
+            rtt_ms: self.rtt.map(|rtt| rtt.as_millis() as u64),
+            state: self.state,
+            version: self.version,
+            features: self.features,
+            penalty: 0,
+            encrypted: false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Reputation {
+    penalties: HashMap<SocketAddr, u32>,
+    bans: HashMap<SocketAddr, Instant>,
+}
+
+impl Reputation {
+    // returns true if this got them banned
+    pub fn penalize(&mut self, addr: SocketAddr, amount: u32) -> bool {
+        if self.is_banned(&addr) {
+            return false;
+        }
+        if self.bans.remove(&addr).is_some() {
+            self.penalties.remove(&addr);
+        }
+
+        let penalty = self.penalties.entry(addr).or_default();
+        *penalty += amount;
+
+        if *penalty >= BAN_THRESHOLD {
+            self.bans.insert(addr, Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn penalty(&self, addr: &SocketAddr) -> u32 {
+        self.penalties.get(addr).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.bans
+            .get(addr)
+            .map(|banned_at| banned_at.elapsed() < BAN_DURATION)
+            .unwrap_or(false)
+    }
+
+    // lets everyone whose ban is up start over
+    pub fn expire(&mut self) {
+        let penalties = &mut self.penalties;
+        self.bans.retain(|addr, banned_at| {
+            let banned = banned_at.elapsed() < BAN_DURATION;
+            if !banned {
+                penalties.remove(addr);
+            }
+            banned
+        });
+    }
+
+    pub fn bans(&self) -> Vec<BanInfo> {
+        self.bans
+            .iter()
+            .map(|(addr, banned_at)| BanInfo {
+                addr: *addr,
+                banned_secs: banned_at.elapsed().as_secs(),
+                penalty: self.penalty(addr),
+            })
+            .collect()
+    }
+
+    // clears the bans of every address on one ip (or everyone's) along with their
+    // penalties, so they start over
+    pub fn clear(&mut self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => {
+                self.penalties.retain(|addr, _| addr.ip() != ip);
+                let banned = self.bans.len();
+                self.bans.retain(|addr, _| addr.ip() != ip);
+                self.bans.len() < banned
+            }
+            None => {
+                self.penalties.clear();
+                self.bans.drain().count() > 0
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn bans_past_the_threshold() {
+        let mut reputation = Reputation::default();
+        let peer = addr("10.0.0.1:25565");
+
+        for _ in 1..BAN_THRESHOLD / CORRUPT_PIECE_PENALTY {
+            assert!(!reputation.penalize(peer, CORRUPT_PIECE_PENALTY));
+        }
+        assert!(reputation.penalize(peer, CORRUPT_PIECE_PENALTY));
+        assert!(reputation.is_banned(&peer));
+        // only that address, not the whole ip
+        assert!(!reputation.is_banned(&addr("10.0.0.1:25566")));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let mut reputation = Reputation::default();
+        let peer = addr("10.0.0.1:25565");
+        reputation.penalize(peer, BAN_THRESHOLD);
+
+        let long_ago = Instant::now().checked_sub(BAN_DURATION).unwrap();
+        reputation.bans.insert(peer, long_ago);
+        assert!(!reputation.is_banned(&peer));
+
+        reputation.expire();
+        assert!(reputation.bans().is_empty());
+        assert_eq!(reputation.penalty(&peer), 0);
+    }
+
+    #[test]
+    fn starts_over_after_a_ban() {
+        let mut reputation = Reputation::default();
+        let peer = addr("10.0.0.1:25565");
+        reputation.penalize(peer, BAN_THRESHOLD);
+
+        let long_ago = Instant::now().checked_sub(BAN_DURATION).unwrap();
+        reputation.bans.insert(peer, long_ago);
+        assert!(!reputation.penalize(peer, MALFORMED_PENALTY));
+        assert_eq!(reputation.penalty(&peer), MALFORMED_PENALTY);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::*;
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PickStrategy {
+    #[default]
+    RarestFirst,
+    Sequential,
+    Random,
+}
+
+impl PickStrategy {
+    pub fn picker(self) -> Box<dyn PiecePicker> {
+        match self {
+            PickStrategy::RarestFirst => Box::new(RarestFirst),
+            PickStrategy::Sequential => Box::new(Sequential),
+            PickStrategy::Random => Box::new(RandomOrder),
+        }
+    }
+}
+
+impl FromStr for PickStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PickStrategy, String> {
+        match s {
+            "rarest" | "rarest-first" => Ok(PickStrategy::RarestFirst),
+            "sequential" => Ok(PickStrategy::Sequential),
+            "random" => Ok(PickStrategy::Random),
+            _ => Err(format!(
+                "unknown strategy {}, expected rarest, sequential or random",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PickStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PickStrategy::RarestFirst => write!(f, "rarest-first"),
+            PickStrategy::Sequential => write!(f, "sequential"),
+            PickStrategy::Random => write!(f, "random"),
+        }
+    }
+}
+
+// how many peers we know of have each piece, kept up to date as haves and bitfields come
+// in so picking doesn't have to ask every peer about every needed piece
+#[derive(Debug, Default)]
+pub struct Availability {
+    counts: HashMap<BoxHash, HashMap<PieceKey, usize>>,
+}
+
+impl Availability {
+    pub fn add(&mut self, id: BoxHash, key: PieceKey) {
+        *self.counts.entry(id).or_default().entry(key).or_default() += 1;
+    }
+
+    pub fn remove(&mut self, id: BoxHash, key: PieceKey) {
+        let counts = match self.counts.get_mut(&id) {
+            Some(counts) => counts,
+            None => return,
+        };
+
+        if let Some(n) = counts.get_mut(&key) {
+            *n -= 1;
+            if *n == 0 {
+                counts.remove(&key);
+            }
+        }
+        if counts.is_empty() {
+            self.counts.remove(&id);
+        }
+    }
+
+    // takes away everything a peer had, when it goes away or sends a new bitfield
+    pub fn remove_all(&mut self, pieces: &HashMap<BoxHash, HashSet<PieceKey>>) {
+        for (id, keys) in pieces {
+            for key in keys {
+                self.remove(*id, *key);
+            }
+        }
+    }
+
+    pub fn of(&self, id: &BoxHash) -> Option<&HashMap<PieceKey, usize>> {
+        self.counts.get(id)
+    }
+}
+
+pub trait PiecePicker {
+    // sorts `needed` so the pieces we should ask for first come first. `availability` is how
+    // many peers we know have each piece
+    fn order(&self, needed: &mut [PieceKey], availability: &HashMap<PieceKey, usize>);
+}
+
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn order(&self, needed: &mut [PieceKey], availability: &HashMap<PieceKey, usize>) {
+        // shuffle first so peers with the same view of the swarm don't all pick the same
+        // piece out of a tie
+        needed.shuffle(&mut rand::thread_rng());
+        needed.sort_by_key(|k| match availability.get(k) {
+            Some(n) if *n > 0 => *n,
+            _ => usize::MAX, // nobody has it, no point asking first
+        });
+    }
+}
+
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn order(&self, needed: &mut [PieceKey], _availability: &HashMap<PieceKey, usize>) {
+        needed.sort();
+    }
+}
+
+pub struct RandomOrder;
+
+impl PiecePicker for RandomOrder {
+    fn order(&self, needed: &mut [PieceKey], _availability: &HashMap<PieceKey, usize>) {
+        needed.shuffle(&mut rand::thread_rng());
+    }
+}
@@ -0,0 +1,37 @@
+use crate::*;
+
+use argon2::Argon2;
+use blake2::Blake2s256;
+
+pub type BoxSecret = [u8; 32];
+pub type Nonce = [u8; 16];
+pub type Proof = [u8; 32];
+
+// the passphrase itself never leaves this machine, only proofs made with what it hashes to.
+// proofs can be overheard, so it's a slow hash salted with the box, to make guessing
+// passphrases offline expensive and only good for one box at a time
+pub fn derive_secret(passphrase: &str, id: &BoxHash) -> BoxSecret {
+    let mut secret = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), id.as_bytes(), &mut secret)
+        .expect("box hashes are long enough to salt with");
+    secret
+}
+
+// covers both ends of the exchange, so an answer to one peer's challenge can't be
+// replayed to us or relayed from someone else who knows the secret
+pub fn prove(
+    secret: &BoxSecret,
+    id: &BoxHash,
+    nonce: &Nonce,
+    challenger: &NodeId,
+    responder: &NodeId,
+) -> Proof {
+    let mut hasher = Blake2s256::new();
+    hasher.update(secret);
+    hasher.update(id);
+    hasher.update(nonce);
+    hasher.update(challenger);
+    hasher.update(responder);
+    hasher.finalize().into()
+}
@@ -1,30 +1,194 @@
+use crate::acl::*;
+use crate::channel::*;
+use crate::crypto::*;
+use crate::dht::*;
 use crate::file::*;
+use crate::hash::HashAlgorithm;
+use crate::hashing::*;
+use crate::peer::*;
+use crate::picker::*;
+use crate::secret::*;
+use crate::tracker::*;
 
-use laminar::{Packet as LaminarPacket, SocketEvent};
-use std::collections::{BTreeMap, HashSet};
+use laminar::{DeliveryGuarantee, Packet as LaminarPacket, SocketEvent};
+use rand::seq::SliceRandom;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
 use std::path::{Path, PathBuf};
 
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
+use ed25519_dalek::SigningKey;
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(90);
+// peer exchange limits, so nobody can use us to flood a network
+pub const PEX_MAX_PEERS: usize = 20;
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_DOWNLOADS_PER_BOX: usize = 8;
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+pub const MAX_PIECE_PROBES: usize = 16;
+// once a box is down to this many pieces, ask several peers for each of them
+pub const ENDGAME_PIECES: usize = 4;
+pub const ENDGAME_MAX_PEERS: usize = 3;
+// opencomputers can get overwhelmed if we go too fast here
+pub const UPLOAD_CHUNK_INTERVAL: Duration = Duration::from_millis(30);
+pub const MAX_QUEUED_UPLOADS: usize = 64;
+// how many peers we upload each box to at once
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+pub const INTEREST_TIMEOUT: Duration = Duration::from_secs(60);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a finished creation waits for someone to ask how it went
+pub const CREATION_TTL: Duration = Duration::from_secs(600);
+// sealed packets in a row that don't open before we give up on a session and start over
+pub const MAX_OPEN_FAILURES: u32 = 8;
+// packets held for a peer while a session with it is being set up
+pub const MAX_HELD_PACKETS: usize = 32;
+
+pub struct DesiredBox {
+    pub path: PathBuf,
+    pub strategy: PickStrategy,
+    pub secret: Option<BoxSecret>,
+}
+
+// a channel we publish to, follow, or both
+#[derive(Default)]
+pub struct Channel {
+    pub latest: Option<SignedRevision>,
+    pub subscription: Option<DesiredBox>,
+    pub publishing: bool,
+    pub current_box: Option<BoxHash>, // the box we're seeding/downloading for it
+}
+
+// a piece we've asked for. usually from one peer, more than one in endgame
+pub struct Download {
+    pub peers: Vec<SocketAddr>,
+    pub started: Instant,
+}
+
+// who sent a chunk (by index) of a piece that failed, and what it hashed to
+type Suspect = (SocketAddr, usize, PieceHash);
+
+// a piece we're sending to a peer, one chunk at a time
+pub struct PendingUpload {
+    pub peer: SocketAddr,
+    pub id: BoxHash,
+    pub key: PieceKey,
+    pub chunks: VecDeque<usize>, // chunk indexes still to send
+}
+
+// a box being created on the hash pool. finished ones are kept until they're asked about
+// once, or for CREATION_TTL
+enum Creation {
+    Hashing(Box<PendingBox>, Option<SigningKey>), // published to the key's channel once it's done
+    Done(BoxHash, Instant),
+    Failed(io::Error, Instant),
+}
+
+impl Creation {
+    fn expired(&self) -> bool {
+        match self {
+            Creation::Hashing(..) => false,
+            Creation::Done(_, at) | Creation::Failed(_, at) => at.elapsed() >= CREATION_TTL,
+        }
+    }
+}
 
 pub struct NyahState {
+    pub node_id: NodeId,
+    identity: Identity,
     pub boxes: BTreeMap<BoxHash, CardboardBox>,
-    pub peers: HashSet<SocketAddr>,
+    pub peers: HashMap<SocketAddr, Peer>,
+    pub static_peers: HashSet<SocketAddr>,
+    pub peer_timeout: Duration,
+    pub trackers: Vec<SocketAddr>,
+    // our own addresses and our networks' broadcast ones, which pex shouldn't send us to
+    pub own_addrs: HashSet<SocketAddr>,
+    pub dht: Dht,
+    dht_outbox: Receiver<LaminarPacket>, // the dht's packets, so they get sealed like ours
     packet_sender: Sender<LaminarPacket>,
-    pub looking_for_boxes: BTreeMap<BoxHash, PathBuf>,
-    filter_from: Option<IpAddr>, // filter events from this address
+    sessions: HashMap<SocketAddr, Session>,
+    // from handshakes they started while we had a session. the old one stays until this
+    // opens something, so a spoofed or replayed handshake can't knock a session out
+    unconfirmed: HashMap<SocketAddr, Session>,
+    open_failures: HashMap<SocketAddr, u32>,
+    handshakes: HashMap<SocketAddr, (StaticSecret, Instant)>, // ones we started
+    early_sealed: HashMap<SocketAddr, Vec<(u64, Vec<u8>)>>,   // beat the reply to our handshake
+    unsent: RefCell<HashMap<SocketAddr, (Instant, Vec<LaminarPacket>)>>, // waiting on a session
+    pub require_encryption: bool,
+    pub looking_for_boxes: BTreeMap<BoxHash, DesiredBox>,
+    pub channels: HashMap<ChannelId, Channel>,
+    pub downloads: HashMap<(BoxHash, PieceKey), Download>,
+    piece_index: HashMap<PieceHash, HashSet<(BoxHash, PieceKey)>>, // where we have each piece
+    pub uploads: VecDeque<PendingUpload>,
+    last_upload: Instant,
+    pub upload_slots: usize,
+    optimistic_unchokes: HashMap<BoxHash, SocketAddr>,
+    last_optimistic_unchoke: Instant,
+    pub reputation: Reputation,
+    pub acl: Acl,
+    contributors: HashMap<(BoxHash, PieceKey), HashMap<usize, SocketAddr>>, // who sent each chunk
+    // chunks of pieces that failed with several senders, and who sent them
+    suspects: HashMap<(BoxHash, PieceKey), Vec<Suspect>>,
+    availability: Availability,
+    hash_pool: HashPool,
+    creations: HashMap<CreationId, Creation>,
+    next_creation: CreationId,
+    next_verification: u64, // so results for a box that was removed and re-added are dropped
 }
 
 impl NyahState {
-    pub fn new(sender: Sender<LaminarPacket>, filter_from: Option<IpAddr>) -> NyahState {
+    pub fn new(
+        sender: Sender<LaminarPacket>,
+        identity: Identity,
+        hash_threads: usize,
+    ) -> NyahState {
+        let node_id = identity.node_id();
+        let (dht_sender, dht_outbox) = crossbeam_channel::unbounded();
+
         NyahState {
+            node_id,
+            identity,
+            dht: Dht::new(node_id, dht_sender),
+            dht_outbox,
             packet_sender: sender,
-            peers: HashSet::new(),
+            sessions: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            open_failures: HashMap::new(),
+            handshakes: HashMap::new(),
+            early_sealed: HashMap::new(),
+            unsent: RefCell::new(HashMap::new()),
+            require_encryption: false,
+            peers: HashMap::new(),
+            static_peers: HashSet::new(),
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            trackers: Vec::new(),
+            own_addrs: HashSet::new(),
             boxes: BTreeMap::new(),
             looking_for_boxes: BTreeMap::new(),
-            filter_from,
+            channels: HashMap::new(),
+            downloads: HashMap::new(),
+            piece_index: HashMap::new(),
+            uploads: VecDeque::new(),
+            last_upload: Instant::now(),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            optimistic_unchokes: HashMap::new(),
+            last_optimistic_unchoke: Instant::now(),
+            reputation: Reputation::default(),
+            acl: Acl::default(),
+            contributors: HashMap::new(),
+            suspects: HashMap::new(),
+            availability: Availability::default(),
+            hash_pool: HashPool::new(hash_threads),
+            creations: HashMap::new(),
+            next_creation: 0,
+            next_verification: 0,
         }
     }
 
@@ -32,16 +196,141 @@ impl NyahState {
         self.boxes.get(&key).map(|v| v.metadata.clone())
     }
 
+    // starts hashing a new box in the background. it's seeded once that's done
     pub fn create_box(
         &mut self,
         box_name: String,
         box_dir: impl AsRef<Path>,
-    ) -> io::Result<BoxHash> {
-        let cardboard_box = CardboardBox::create(box_name, box_dir)?;
+        options: BoxOptions,
+    ) -> io::Result<CreationId> {
+        let pending = PendingBox::new(box_name, box_dir, &options)?;
+        self.start_creation(pending, None)
+    }
+
+    fn start_creation(
+        &mut self,
+        pending: PendingBox,
+        publish: Option<SigningKey>,
+    ) -> io::Result<CreationId> {
+        self.creations.retain(|_, c| !c.expired());
+
+        let id = self.next_creation;
+        self.next_creation += 1;
+
+        pending.submit(&self.hash_pool, Owner::Creation(id));
+        let done = pending.is_done();
+        self.creations
+            .insert(id, Creation::Hashing(Box::new(pending), publish));
+
+        // nothing to hash, so no results are coming to finish it
+        if done {
+            self.finish_creation(id)?;
+        }
+
+        Ok(id)
+    }
+
+    fn finish_creation(&mut self, id: CreationId) -> io::Result<()> {
+        let (pending, publish) = match self.creations.remove(&id) {
+            Some(Creation::Hashing(pending, publish)) => (pending, publish),
+            _ => return Ok(()),
+        };
+
+        let cardboard_box = match pending.finish() {
+            Ok(cardboard_box) => cardboard_box,
+            Err(e) => {
+                self.creations
+                    .insert(id, Creation::Failed(e, Instant::now()));
+                return Ok(());
+            }
+        };
+
         let hash = cardboard_box.hash;
         self.boxes.insert(hash, cardboard_box);
+        self.index_pieces(hash);
+        self.creations
+            .insert(id, Creation::Done(hash, Instant::now()));
+
+        if let Some(key) = publish {
+            self.publish_revision(&key, hash)?;
+        }
 
-        Ok(hash)
+        Ok(())
+    }
+
+    // how a creation is going, the box it made, or why it couldn't. None if we don't know
+    // of it, which a finished one won't be after this has told someone how it went
+    pub fn get_creation(&mut self, id: CreationId) -> Option<io::Result<BoxState>> {
+        self.creations.retain(|_, c| !c.expired());
+
+        if let Creation::Hashing(pending, _) = self.creations.get(&id)? {
+            return Some(Ok(pending.get_download_state()));
+        }
+
+        match self.creations.remove(&id)? {
+            Creation::Done(hash, _) => self.boxes.get(&hash).map(|b| Ok(b.get_download_state())),
+            Creation::Failed(e, _) => Some(Err(e)),
+            Creation::Hashing(..) => unreachable!(),
+        }
+    }
+
+    // our boxes, and the ones still being created
+    pub fn get_box_states(&self) -> Vec<BoxState> {
+        let creating = self.creations.values().filter_map(|c| match c {
+            Creation::Hashing(pending, _) => Some(pending.get_download_state()),
+            _ => None,
+        });
+
+        self.boxes
+            .values()
+            .map(|b| b.get_download_state())
+            .chain(creating)
+            .collect()
+    }
+
+    // hands whatever the hash pool has finished to the boxes it's for
+    pub fn poll_hashing(&mut self) -> io::Result<()> {
+        let results: Vec<JobResult> = self.hash_pool.results().collect();
+
+        for result in results {
+            match result.owner {
+                Owner::Creation(id) => {
+                    let done = match self.creations.get_mut(&id) {
+                        Some(Creation::Hashing(pending, _)) => result
+                            .output
+                            .and_then(|output| pending.add(result.file_index, result.task, output)),
+                        _ => continue,
+                    };
+
+                    match done {
+                        Ok(true) => self.finish_creation(id)?,
+                        Ok(false) => (),
+                        Err(e) => {
+                            self.creations
+                                .insert(id, Creation::Failed(e, Instant::now()));
+                        }
+                    }
+                }
+                Owner::Box(hash, generation) => {
+                    let done = match self.boxes.get_mut(&hash) {
+                        Some(b) => b.add_verified(
+                            generation,
+                            result.file_index,
+                            result.task,
+                            result.output,
+                        ),
+                        None => false,
+                    };
+
+                    if done {
+                        self.index_pieces(hash);
+                        self.request_bitfields(hash)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub fn add_box(
@@ -49,186 +338,2217 @@ impl NyahState {
         box_dir: impl AsRef<Path>,
         hash: BoxHash,
         metadata: CardboardMetadata,
+        strategy: PickStrategy,
+        secret: Option<BoxSecret>,
     ) -> io::Result<()> {
-        let cardboard_box = CardboardBox::from_metadata(box_dir, hash, metadata)?;
+        let mut cardboard_box = CardboardBox::from_metadata(box_dir, hash, metadata)?;
+        cardboard_box.strategy = strategy;
+        cardboard_box.secret = secret;
+        cardboard_box.submit_verification(&self.hash_pool, self.next_verification);
+        self.next_verification += 1;
         let hash = cardboard_box.hash;
         self.boxes.insert(hash, cardboard_box);
+        self.index_pieces(hash);
 
         Ok(())
     }
 
-    pub fn add_desired_box(&mut self, box_hash: BoxHash, box_dir: impl AsRef<Path>) {
-        self.looking_for_boxes
-            .insert(box_hash, box_dir.as_ref().to_owned());
+    pub fn add_desired_box(
+        &mut self,
+        box_hash: BoxHash,
+        box_dir: impl AsRef<Path>,
+        strategy: PickStrategy,
+        passphrase: Option<String>,
+    ) {
+        self.looking_for_boxes.insert(
+            box_hash,
+            DesiredBox {
+                path: box_dir.as_ref().to_owned(),
+                strategy,
+                secret: passphrase.as_deref().map(|p| derive_secret(p, &box_hash)),
+            },
+        );
     }
 
-    pub fn handle_packet(&mut self, event: SocketEvent) -> io::Result<()> {
-        match event {
-            SocketEvent::Packet(p) => {
-                if self
-                    .filter_from
-                    .as_ref()
-                    .map(|ip| ip == &p.addr().ip())
-                    .unwrap_or(false)
-                {
-                    return Ok(());
-                }
+    // stops seeding/downloading a box. its files are left where they are
+    pub fn remove_box(&mut self, box_hash: BoxHash) -> io::Result<bool> {
+        let was_box = self.boxes.remove(&box_hash).is_some();
+        let was_desired = self.looking_for_boxes.remove(&box_hash).is_some();
 
-                self.handle_msg(p.addr(), rmp_serde::from_read_ref(p.payload()).unwrap())?;
-            }
-            // SocketEvent::Disconnect(p) => {
-            //     self.peers.remove(&p);
-            // }
-            _ => (),
+        let keys: Vec<(BoxHash, PieceKey)> = self
+            .downloads
+            .keys()
+            .filter(|(id, _)| *id == box_hash)
+            .copied()
+            .collect();
+        for key in keys {
+            self.cancel_download(key)?;
         }
 
-        Ok(())
+        self.uploads.retain(|u| u.id != box_hash);
+        self.suspects.retain(|(id, _), _| *id != box_hash);
+
+        for locations in self.piece_index.values_mut() {
+            locations.retain(|(id, _)| *id != box_hash);
+        }
+        self.piece_index
+            .retain(|_, locations| !locations.is_empty());
+
+        Ok(was_box || was_desired)
     }
 
-    pub fn handle_msg(&mut self, from: SocketAddr, message: Message) -> io::Result<()> {
-        use Message::*;
+    // adds every verified piece of a box to the piece index
+    fn index_pieces(&mut self, id: BoxHash) {
+        let b = match self.boxes.get(&id) {
+            Some(b) => b,
+            None => return,
+        };
 
-        match message {
-            SearchingForPeers => {
-                self.peers.insert(from);
-                self.send_packet(ImHere.to_packet(from))?
-            }
-            ImHere => {
-                self.peers.insert(from);
-            }
-            FindMetadata(hash) => {
-                if let Some(metadata) = self.get_metadata(hash) {
-                    self.send_packet(GotMetadata(hash, metadata).to_packet(from))?;
-                }
-            }
-            GotMetadata(hash, metadata) => {
-                if let Some(path) = self.looking_for_boxes.remove(&hash) {
-                    self.add_box(path, hash, metadata)?;
-                }
-            }
-            FindPiece {
-                id,
-                file_index,
-                piece_index,
-            } => {
-                if let Some(file) = self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
-                    if file.has_piece(piece_index) {
-                        self.send_packet(
-                            GotPiece {
-                                id,
-                                file_index,
-                                piece_index,
-                            }
-                            .to_packet(from),
-                        )?;
-                    }
-                }
-            }
-            GotPiece {
-                id,
-                file_index,
-                piece_index,
-            } => {
-                if let Some(file) = self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
-                    if !file.has_piece(piece_index) {
-                        self.send_packet(
-                            StartDownload {
-                                id,
-                                file_index,
-                                piece_index,
-                            }
-                            .to_packet(from),
-                        )?;
-                    }
-                }
+        for key in b.verified_piece_keys() {
+            if let Some(hash) = b.piece_hash(key) {
+                self.piece_index.entry(hash).or_default().insert((id, key));
             }
-            StartDownload {
-                id,
-                file_index,
-                piece_index,
-            } => {
-                if let Some(data) = self
-                    .boxes
-                    .get(&id)
-                    .and_then(|b| b.files.get(file_index))
-                    .and_then(|f| f.read_piece(piece_index))
-                {
-                    for (i, ch) in data.chunks(CHUNK_SIZE).enumerate() {
-                        std::thread::sleep(Duration::from_millis(30)); // opencomputers can get overwhelmed if we go too fast here
+        }
+    }
 
-                        self.send_packet(
-                            Upload {
-                                id,
-                                file_index,
-                                piece_index,
-                                chunk_index: i,
-                                buf: ch.to_vec(),
-                            }
-                            .to_packet(from),
-                        )?;
-                    }
+    // copies needed pieces out of wherever else we already have them verified, so pieces
+    // shared between boxes only get downloaded once
+    fn dedup_pieces(&mut self) -> io::Result<()> {
+        let mut copies: Vec<((BoxHash, PieceKey), (BoxHash, PieceKey))> = Vec::new();
+        for b in self.boxes.values() {
+            for key in b.needed_piece_keys() {
+                let source = b
+                    .piece_hash(key)
+                    .and_then(|hash| self.piece_index.get(&hash))
+                    .and_then(|locations| locations.iter().find(|l| **l != (b.hash, key)));
+
+                if let Some(source) = source {
+                    copies.push(((b.hash, key), *source));
                 }
             }
-            Upload {
-                id,
-                file_index,
-                piece_index,
-                chunk_index,
-                buf,
-            } => {
-                if let Some(file) = self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
-                    if !file.has_piece(piece_index) {
-                        file.write_chunk(piece_index, chunk_index, &buf);
-                    }
+        }
+
+        for ((id, (file_index, piece_index)), (source_id, (source_file, source_piece))) in copies {
+            let data = match self
+                .boxes
+                .get(&source_id)
+                .and_then(|b| b.files.get(source_file))
+                .and_then(|f| f.read_piece(source_piece))
+            {
+                Some(data) => data,
+                None => continue,
+            };
+
+            let copied = self
+                .boxes
+                .get(&id)
+                .and_then(|b| b.files.get(file_index))
+                .map(|f| f.write_piece(piece_index, data))
+                .unwrap_or(false);
+            let size = data.len() as u64;
+
+            if copied {
+                let key = (id, (file_index, piece_index));
+                if let Some(b) = self.boxes.get_mut(&id) {
+                    b.bytes_saved += size;
                 }
+                self.index_piece(key);
+                self.cancel_download(key)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn search_for_metadata(&self) -> io::Result<()> {
-        for k in self.looking_for_boxes.keys() {
-            for peer in &self.peers {
-                self.send_packet(Message::FindMetadata(*k).to_packet(*peer))?;
-            }
+    fn index_piece(&mut self, (id, key): (BoxHash, PieceKey)) {
+        if let Some(hash) = self.boxes.get(&id).and_then(|b| b.piece_hash(key)) {
+            self.piece_index.entry(hash).or_default().insert((id, key));
+        }
+    }
+
+    pub fn set_strategy(&mut self, box_hash: BoxHash, strategy: PickStrategy) -> bool {
+        if let Some(b) = self.boxes.get_mut(&box_hash) {
+            b.strategy = strategy;
+        } else if let Some(desired) = self.looking_for_boxes.get_mut(&box_hash) {
+            desired.strategy = strategy;
+        } else {
+            return false;
+        }
+
+        true
+    }
+
+    // creates a box, and publishes it as the channel's next revision once it's hashed
+    pub fn publish(
+        &mut self,
+        key_path: impl AsRef<Path>,
+        box_name: String,
+        box_dir: impl AsRef<Path>,
+    ) -> io::Result<CreationId> {
+        let key = load_key(key_path)?;
+        let pending = PendingBox::new(box_name, box_dir, &BoxOptions::default())?;
+
+        self.start_creation(pending, Some(key))
+    }
+
+    // makes a box we've made the newest revision of the key's channel, and tells everyone
+    // about it
+    fn publish_revision(&mut self, key: &SigningKey, hash: BoxHash) -> io::Result<()> {
+        let id = key.verifying_key().to_bytes();
+
+        // timestamps, so they keep going up even if we restart and forget the last one
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let channel = self.channels.entry(id).or_default();
+        let seq = channel
+            .latest
+            .as_ref()
+            .map(|l| l.revision.seq + 1)
+            .unwrap_or(0)
+            .max(now);
+
+        let signed = SignedRevision::sign(
+            key,
+            Revision {
+                seq,
+                box_hash: hash,
+                metadata: self.boxes[&hash].metadata.clone(),
+            },
+        );
+        channel.latest = Some(signed.clone());
+        channel.publishing = true;
+
+        if let Some(old) = channel.current_box.replace(hash).filter(|old| *old != hash) {
+            self.remove_box(old)?;
+        }
+
+        for peer in self
+            .peers
+            .values()
+            .filter(|p| p.is_connected() && p.supports(Features::CHANNELS))
+        {
+            self.send_packet(Message::ChannelUpdate(id, signed.clone()).to_packet(peer.addr))?;
         }
 
         Ok(())
     }
 
-    pub fn search_for_pieces(&self) -> io::Result<()> {
-        for b in self.boxes.values() {
-            for (file_index, piece_indexes) in b.needed_pieces() {
-                for piece_index in piece_indexes {
-                    for peer in &self.peers {
-                        self.send_packet(
-                            Message::FindPiece {
-                                id: b.hash,
-                                file_index,
-                                piece_index,
-                            }
-                            .to_packet(*peer),
-                        )?;
-                        std::thread::sleep(Duration::from_millis(10));
-                    }
-                }
+    pub fn subscribe(&mut self, id: ChannelId, box_dir: impl AsRef<Path>, strategy: PickStrategy) {
+        self.channels.entry(id).or_default().subscription = Some(DesiredBox {
+            path: box_dir.as_ref().to_owned(),
+            strategy,
+            secret: None,
+        });
+    }
+
+    // the channel's current box stays around, same as removing a box leaves its files
+    pub fn unsubscribe(&mut self, id: ChannelId) -> bool {
+        self.channels.remove(&id).is_some()
+    }
+
+    pub fn get_channel_info(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .iter()
+            .map(|(id, c)| ChannelInfo {
+                id: *id,
+                seq: c.latest.as_ref().map(|l| l.revision.seq),
+                box_hash: c.latest.as_ref().map(|l| l.revision.box_hash),
+                publishing: c.publishing,
+                subscribed: c.subscription.as_ref().map(|s| s.path.clone()),
+            })
+            .collect()
+    }
+
+    // swaps a subscribed channel's box over to its latest revision
+    fn follow_channel(&mut self, id: ChannelId) -> io::Result<()> {
+        let channel = match self.channels.get_mut(&id) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let (subscription, latest) = match (&channel.subscription, &channel.latest) {
+            (Some(subscription), Some(latest)) => (subscription, latest),
+            _ => return Ok(()),
+        };
+
+        let hash = latest.revision.box_hash;
+        if channel.current_box == Some(hash) {
+            return Ok(());
+        }
+
+        let path = subscription.path.clone();
+        let strategy = subscription.strategy;
+        let metadata = latest.revision.metadata.clone();
+        let old = channel.current_box.replace(hash);
+
+        // the new revision's files get opened over the old one's, so it stays mapped until
+        // anything that moved is copied over. only what's actually new gets downloaded
+        let previous = old.and_then(|old| self.boxes.remove(&old));
+        let moved = match &previous {
+            Some(previous) => previous.pieces_for(&metadata),
+            None => HashMap::new(),
+        };
+
+        if let Some(old) = old {
+            self.remove_box(old)?;
+        }
+        self.add_box(path, hash, metadata, strategy, None)?;
+        if let (Some(b), Some(previous)) = (self.boxes.get(&hash), &previous) {
+            b.reuse_pieces(previous, &moved);
+        }
+        drop(previous);
+        self.index_pieces(hash);
+
+        self.request_bitfields(hash)
+    }
+
+    // asks around for newer revisions of the channels we follow
+    pub fn poll_channels(&self) -> io::Result<()> {
+        for (id, channel) in self
+            .channels
+            .iter()
+            .filter(|(_, c)| c.subscription.is_some())
+        {
+            let seq = channel.latest.as_ref().map(|l| l.revision.seq).unwrap_or(0);
+
+            for peer in self
+                .peers
+                .values()
+                .filter(|p| p.is_connected() && p.supports(Features::CHANNELS))
+            {
+                self.send_packet(Message::FindChannel(*id, seq).to_packet(peer.addr))?;
             }
         }
 
         Ok(())
     }
 
-    pub fn search_for_peers(&self, addr: SocketAddr) -> io::Result<()> {
-        self.send_packet(LaminarPacket::unreliable(
-            addr,
-            rmp_serde::to_vec(&Message::SearchingForPeers).unwrap(),
-        ))
+    // our node id comes from our key, so the saved contacts are re-bucketed around it
+    pub fn load_dht(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        for contact in RoutingTable::load(path)?.contacts() {
+            self.dht.add_contact(contact);
+        }
+
+        Ok(())
     }
 
-    fn send_packet(&self, packet: LaminarPacket) -> io::Result<()> {
-        self.packet_sender
-            .send(packet)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    pub fn save_dht(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.dht.table.save(path)
+    }
+
+    // false if the acl won't let us talk to it
+    pub fn add_peer(&mut self, addr: SocketAddr) -> io::Result<bool> {
+        if !self.acl.permits(&addr.ip()) {
+            return Ok(false);
+        }
+
+        self.static_peers.insert(addr);
+        self.search_for_peers(addr)?;
+        Ok(true)
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) -> bool {
+        let was_static = self.static_peers.remove(&addr);
+        self.drop_session(addr);
+        self.drop_peer(addr) || was_static
+    }
+
+    fn drop_session(&mut self, addr: SocketAddr) {
+        self.sessions.remove(&addr);
+        self.unconfirmed.remove(&addr);
+        self.open_failures.remove(&addr);
+        self.early_sealed.remove(&addr);
+        self.unsent.get_mut().remove(&addr);
+    }
+
+    // forgets a peer, along with the pieces it was counted as having
+    fn drop_peer(&mut self, addr: SocketAddr) -> bool {
+        match self.peers.remove(&addr) {
+            Some(peer) => {
+                self.availability.remove_all(&peer.pieces);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn connected_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers
+            .values()
+            .filter(|p| p.is_connected())
+            .map(|p| &p.addr)
+    }
+
+    pub fn get_peer_info(&self) -> Vec<PeerInfo> {
+        self.peers
+            .values()
+            .map(|p| PeerInfo {
+                penalty: self.reputation.penalty(&p.addr),
+                encrypted: self.sessions.contains_key(&p.addr),
+                ..p.info()
+            })
+            .collect()
+    }
+
+    pub fn penalize(&mut self, addr: SocketAddr, amount: u32) {
+        if self.reputation.penalize(addr, amount) {
+            self.forget_peers(|a| a == addr);
+        }
+    }
+
+    // anyone can put a peer's address on a datagram, so a bad message only counts against
+    // them if it came through our session with them. once there's a session, the only
+    // plaintext let through is hellos and handshakes (see plaintext_allowed), which are
+    // never penalized
+    fn penalize_malformed(&mut self, addr: SocketAddr) {
+        if self.sessions.contains_key(&addr) {
+            self.penalize(addr, MALFORMED_PENALTY);
+        }
+    }
+
+    pub fn add_acl_rule(&mut self, kind: AclKind, cidr: Cidr) {
+        self.acl.add(kind, cidr);
+
+        let acl = self.acl.clone();
+        self.forget_peers(|addr| !acl.permits(&addr.ip()));
+    }
+
+    // drops everything we had going on with peers that got banned, or that the acl now
+    // denies
+    fn forget_peers(&mut self, gone: impl Fn(SocketAddr) -> bool) {
+        let addrs: Vec<SocketAddr> = self
+            .peers
+            .keys()
+            .filter(|addr| gone(**addr))
+            .copied()
+            .collect();
+
+        for addr in addrs {
+            self.drop_peer(addr);
+            self.drop_session(addr);
+            self.dht.table.remove(addr);
+        }
+
+        for download in self.downloads.values_mut() {
+            download.peers.retain(|p| !gone(*p));
+        }
+        self.downloads.retain(|_, d| !d.peers.is_empty());
+        self.uploads.retain(|u| !gone(u.peer));
+    }
+
+    // drops peers we haven't heard from in `peer_timeout`. static peers stay in
+    // `static_peers` so they'll get picked back up if they come back
+    pub fn expire_peers(&mut self) {
+        self.reputation.expire();
+
+        let expired: Vec<SocketAddr> = self
+            .peers
+            .values()
+            .filter(|p| p.is_expired(self.peer_timeout))
+            .map(|p| p.addr)
+            .collect();
+        for addr in expired {
+            self.drop_peer(addr);
+            self.drop_session(addr);
+        }
+        self.sessions
+            .retain(|addr, _| self.peers.contains_key(addr));
+        self.handshakes
+            .retain(|_, (_, started)| started.elapsed() < HANDSHAKE_TIMEOUT);
+        self.early_sealed
+            .retain(|addr, _| self.handshakes.contains_key(addr));
+        self.unsent
+            .get_mut()
+            .retain(|_, (queued, _)| queued.elapsed() < HANDSHAKE_TIMEOUT);
+    }
+
+    // returns None if the node is us, or a node we already know through another address
+    fn saw_node(&mut self, addr: SocketAddr, hello: &Hello) -> Option<&mut Peer> {
+        let node_id = hello.node_id;
+        let legacy = hello.version == 0;
+        if !legacy && node_id == self.node_id {
+            return None;
+        }
+
+        if let Some(other) = self
+            .peers
+            .values()
+            .find(|p| !legacy && p.addr != addr && p.node_id == Some(node_id))
+            .map(|p| p.addr)
+        {
+            if self.peers[&other].is_connected() {
+                return None;
+            }
+
+            // the old address went away, this one replaces it
+            self.drop_peer(other);
+        }
+
+        if hello.features.contains(Features::DHT) {
+            self.dht.add_contact(Contact { id: node_id, addr });
+        }
+
+        let peer = self.peers.entry(addr).or_insert_with(|| Peer::new(addr));
+        peer.hello(hello);
+        peer.seen();
+        Some(peer)
+    }
+
+    fn box_secret(&self, id: &BoxHash) -> Option<BoxSecret> {
+        match self.boxes.get(id) {
+            Some(b) => b.secret,
+            None => self.looking_for_boxes.get(id).and_then(|d| d.secret),
+        }
+    }
+
+    // whether `from` may ask us about box `id`. peers that haven't proven they know a
+    // private box's secret yet get challenged to
+    fn authorized(&mut self, from: SocketAddr, id: BoxHash) -> io::Result<bool> {
+        if self
+            .boxes
+            .get(&id)
+            .map(|b| b.secret.is_none())
+            .unwrap_or(true)
+        {
+            return Ok(true);
+        }
+
+        let peer = match self.peers.get_mut(&from) {
+            Some(peer) => peer,
+            None => return Ok(false),
+        };
+        if peer.authorized.contains(&id) {
+            return Ok(true);
+        }
+
+        // still waiting on the last one, and a new nonce would fail the answer in flight
+        let waiting = peer
+            .challenges
+            .get(&id)
+            .map(|(_, sent)| sent.elapsed() < CHALLENGE_TIMEOUT)
+            .unwrap_or(false);
+        let backing_off = peer
+            .bad_proofs
+            .get(&id)
+            .map(|at| at.elapsed() < BAD_PROOF_BACKOFF)
+            .unwrap_or(false);
+        if !peer.supports(Features::PRIVATE) || waiting || backing_off {
+            return Ok(false);
+        }
+
+        let nonce: Nonce = rand::random();
+        peer.challenges.insert(id, (nonce, Instant::now()));
+        self.send_packet(Message::Challenge(id, nonce).to_packet(from))?;
+
+        Ok(false)
+    }
+
+    fn peer_has_box(&mut self, addr: SocketAddr, hash: BoxHash) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.boxes.insert(hash);
+        }
+    }
+
+    fn set_peer_state(&mut self, addr: SocketAddr, state: ConnectionState) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.state = state;
+        }
+    }
+
+    pub fn handle_packet(&mut self, event: SocketEvent) -> io::Result<()> {
+        match event {
+            SocketEvent::Packet(p) => {
+                if self.reputation.is_banned(&p.addr()) || !self.acl.permits(&p.addr().ip()) {
+                    return Ok(());
+                }
+
+                match Message::decode(p.payload()) {
+                    Ok(Message::Sealed(counter, ciphertext)) => {
+                        self.handle_sealed(p.addr(), counter, ciphertext)?
+                    }
+                    Ok(msg) => {
+                        if self.plaintext_allowed(p.addr(), &msg) {
+                            self.saw_peer(p.addr());
+                            self.handle_msg(p.addr(), msg)?
+                        }
+                    }
+                    // could be from a newer (or much older) node, or not from who it says at
+                    // all. garbage in a session is held against them in handle_sealed
+                    Err(_) => (),
+                }
+            }
+            SocketEvent::Timeout(addr) => self.set_peer_state(addr, ConnectionState::TimedOut),
+            SocketEvent::Disconnect(addr) => {
+                self.set_peer_state(addr, ConnectionState::Disconnected)
+            }
+            _ => (),
+        }
+
+        self.flush_dht()
+    }
+
+    // only for packets we know are really from them, so spoofed ones can't keep a dead
+    // peer around
+    fn saw_peer(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.seen();
+        }
+    }
+
+    fn handle_sealed(
+        &mut self,
+        from: SocketAddr,
+        counter: u64,
+        ciphertext: Vec<u8>,
+    ) -> io::Result<()> {
+        let handshaking = self.handshakes.contains_key(&from);
+        if !handshaking
+            && !self.sessions.contains_key(&from)
+            && !self.unconfirmed.contains_key(&from)
+        {
+            // they think we have a session, so one of us restarted. set up a new one
+            return self.start_handshake(from, true);
+        }
+
+        let mut plaintext = self
+            .sessions
+            .get_mut(&from)
+            .and_then(|session| session.open(counter, &ciphertext));
+        if plaintext.is_none() {
+            if let Some(mut next) = self.unconfirmed.remove(&from) {
+                plaintext = next.open(counter, &ciphertext);
+                if plaintext.is_some() {
+                    // they've shown they really have the new keys
+                    self.sessions.insert(from, next);
+                } else {
+                    self.unconfirmed.insert(from, next);
+                }
+            }
+        }
+
+        // forged, replayed, or from before a rekey. a few are normal, but a run of them
+        // means our sessions don't match any more
+        let plaintext = match plaintext {
+            Some(plaintext) => plaintext,
+            // they got our handshake and answered, but this beat the answer here
+            None if handshaking => {
+                let held = self.early_sealed.entry(from).or_default();
+                if held.len() < MAX_HELD_PACKETS {
+                    held.push((counter, ciphertext));
+                }
+                return Ok(());
+            }
+            None => {
+                let failures = self.open_failures.entry(from).or_default();
+                *failures += 1;
+                if *failures >= MAX_OPEN_FAILURES {
+                    self.open_failures.remove(&from);
+                    self.start_handshake(from, true)?;
+                }
+                return Ok(());
+            }
+        };
+        self.open_failures.remove(&from);
+        self.saw_peer(from);
+
+        match rmp_serde::from_slice(&plaintext) {
+            Ok(Message::Sealed(..)) => Ok(()),
+            Ok(msg) => self.handle_msg(from, msg),
+            Err(_) => {
+                self.penalize_malformed(from);
+                Ok(())
+            }
+        }
+    }
+
+    // discovery, handshakes and trackers always happen in the clear. anything else only
+    // if we're allowing plaintext and haven't got a session, so nobody can downgrade one
+    fn plaintext_allowed(&self, from: SocketAddr, msg: &Message) -> bool {
+        use Message::*;
+
+        match msg {
+            SearchingForPeers(_) | ImHere(_) | Handshake { .. } | HandshakeReply { .. } => true,
+            BoxPeers(..) => self.trackers.contains(&from),
+            _ => !self.require_encryption && !self.sessions.contains_key(&from),
+        }
+    }
+
+    // sends what was held back while the session with `addr` was being set up
+    fn session_ready(&mut self, addr: SocketAddr) -> io::Result<()> {
+        if let Some(held) = self.early_sealed.remove(&addr) {
+            for (counter, ciphertext) in held {
+                self.handle_sealed(addr, counter, ciphertext)?;
+            }
+        }
+
+        let unsent = self.unsent.get_mut().remove(&addr);
+        for packet in unsent.map(|(_, packets)| packets).unwrap_or_default() {
+            self.send_packet(packet)?;
+        }
+
+        Ok(())
+    }
+
+    // only the end with the lower node id starts one, unless `force`d because the other
+    // end's session is stale
+    fn start_handshake(&mut self, addr: SocketAddr, force: bool) -> io::Result<()> {
+        let node_id = match self.peers.get(&addr) {
+            Some(peer) if peer.supports(Features::ENCRYPTION) => peer.node_id,
+            _ => return Ok(()),
+        };
+
+        match node_id {
+            Some(id) if force || self.node_id < id => (),
+            _ => return Ok(()),
+        }
+
+        if (!force && self.sessions.contains_key(&addr))
+            || self
+                .handshakes
+                .get(&addr)
+                .map(|(_, started)| started.elapsed() < HANDSHAKE_TIMEOUT)
+                .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let ephemeral = ephemeral();
+        self.send_plain(
+            Message::Handshake {
+                static_key: self.identity.public.to_bytes(),
+                ephemeral: PublicKey::from(&ephemeral).to_bytes(),
+            }
+            .to_packet(addr),
+        )?;
+        self.handshakes.insert(addr, (ephemeral, Instant::now()));
+
+        Ok(())
+    }
+
+    // the key they handshake with has to match the node id they said hello with
+    fn check_static_key(&self, from: SocketAddr, static_key: [u8; 32]) -> Option<PublicKey> {
+        let node_id = self.peers.get(&from)?.node_id?;
+        let key = PublicKey::from(static_key);
+
+        // handshakes are always in the clear, so a wrong key could be anyone's
+        (node_id_for(&key) == node_id).then_some(key)
+    }
+
+    pub fn handle_msg(&mut self, from: SocketAddr, message: Message) -> io::Result<()> {
+        use Message::*;
+
+        match message {
+            SearchingForPeers(hello) => {
+                if hello.version == 0 || hello.node_id != self.node_id {
+                    self.saw_node(from, &hello);
+                    // older nodes can't read a hello, so answer them how they asked
+                    if hello.version == 0 {
+                        self.send_plain(LegacyHello::ImHere.to_packet(from))?;
+                    } else {
+                        self.send_plain(ImHere(Hello::new(self.node_id)).to_packet(from))?;
+                    }
+                    self.start_handshake(from, false)?;
+                }
+            }
+            ImHere(hello) => {
+                if let Some(peer) = self.saw_node(from, &hello) {
+                    peer.ponged();
+                }
+                self.start_handshake(from, false)?;
+            }
+            Handshake {
+                static_key,
+                ephemeral: their_ephemeral,
+            } => {
+                let their_static = match self.check_static_key(from, static_key) {
+                    Some(key) => key,
+                    None => return Ok(()),
+                };
+
+                // we both started one at once. the lower node id's wins
+                if self.handshakes.contains_key(&from) && self.node_id < node_id_for(&their_static)
+                {
+                    return Ok(());
+                }
+                self.handshakes.remove(&from);
+
+                let ours = ephemeral();
+                if let Some(session) = Session::new(
+                    false,
+                    &self.identity,
+                    &ours,
+                    &their_static,
+                    &PublicKey::from(their_ephemeral),
+                ) {
+                    self.send_plain(
+                        HandshakeReply {
+                            static_key: self.identity.public.to_bytes(),
+                            ephemeral: PublicKey::from(&ours).to_bytes(),
+                        }
+                        .to_packet(from),
+                    )?;
+
+                    // anyone can send a handshake claiming to be them, so a working
+                    // session isn't given up until the new one proves itself
+                    match self.sessions.entry(from) {
+                        Entry::Occupied(_) => {
+                            self.unconfirmed.insert(from, session);
+                        }
+                        Entry::Vacant(slot) => {
+                            slot.insert(session);
+                            self.session_ready(from)?;
+                        }
+                    }
+                }
+            }
+            HandshakeReply {
+                static_key,
+                ephemeral: their_ephemeral,
+            } => {
+                let (ours, _) = match self.handshakes.remove(&from) {
+                    Some(handshake) => handshake,
+                    None => return Ok(()),
+                };
+                let their_static = match self.check_static_key(from, static_key) {
+                    Some(key) => key,
+                    None => return Ok(()),
+                };
+
+                // we asked for this one, so it replaces whatever we had
+                if let Some(session) = Session::new(
+                    true,
+                    &self.identity,
+                    &ours,
+                    &their_static,
+                    &PublicKey::from(their_ephemeral),
+                ) {
+                    self.unconfirmed.remove(&from);
+                    self.sessions.insert(from, session);
+                    self.session_ready(from)?;
+                }
+                // if that didn't work they're stale, and so are the keys they were sealed with
+                self.early_sealed.remove(&from);
+            }
+            // only ever unwrapped once, in handle_sealed
+            Sealed(..) => (),
+            FindChannel(id, seq) => {
+                if let Some(latest) = self
+                    .channels
+                    .get(&id)
+                    .and_then(|c| c.latest.as_ref())
+                    .filter(|l| l.revision.seq > seq)
+                {
+                    self.send_packet(ChannelUpdate(id, latest.clone()).to_packet(from))?;
+                }
+            }
+            ChannelUpdate(id, signed) => {
+                if !signed.verify(&id) {
+                    self.penalize_malformed(from);
+                    return Ok(());
+                }
+                self.peer_has_box(from, signed.revision.box_hash);
+
+                // we only keep track of channels someone asked us to
+                let channel = match self.channels.get_mut(&id) {
+                    Some(channel) => channel,
+                    None => return Ok(()),
+                };
+                if channel
+                    .latest
+                    .as_ref()
+                    .map(|l| l.revision.seq >= signed.revision.seq)
+                    .unwrap_or(false)
+                {
+                    return Ok(());
+                }
+
+                channel.latest = Some(signed);
+                self.follow_channel(id)?;
+            }
+            GetPeers(hash) => {
+                let peer = match self.peers.get_mut(&from) {
+                    Some(peer) => peer,
+                    None => return Ok(()),
+                };
+
+                if peer
+                    .last_pex_request
+                    .get(&hash)
+                    .map(|t| t.elapsed() < PEX_INTERVAL)
+                    .unwrap_or(false)
+                {
+                    return Ok(());
+                }
+                peer.last_pex_request.insert(hash, Instant::now());
+
+                let mut known: Vec<&Peer> = self
+                    .peers
+                    .values()
+                    .filter(|p| p.addr != from && p.is_connected())
+                    .filter(|p| hash.map(|h| p.boxes.contains(&h)).unwrap_or(true))
+                    .collect();
+                known.sort_by_key(|p| p.last_seen.elapsed());
+
+                let addrs = known
+                    .into_iter()
+                    .take(PEX_MAX_PEERS)
+                    .map(|p| p.addr)
+                    .collect();
+                self.send_packet(KnownPeers(hash, addrs).to_packet(from))?;
+            }
+            KnownPeers(hash, addrs) => {
+                // only take answers to questions we actually asked
+                if !self
+                    .peers
+                    .get_mut(&from)
+                    .map(|p| p.pex_pending.remove(&hash))
+                    .unwrap_or(false)
+                {
+                    return Ok(());
+                }
+
+                let new_addrs: Vec<SocketAddr> = addrs
+                    .into_iter()
+                    .filter(|addr| !self.peers.contains_key(addr))
+                    .filter(|addr| self.could_be_peer(from, addr))
+                    .take(PEX_MAX_PEERS)
+                    .collect();
+
+                for addr in new_addrs {
+                    self.search_for_peers(addr)?;
+                }
+            }
+            BoxPeers(_, addrs) => {
+                if !self.trackers.contains(&from) {
+                    return Ok(());
+                }
+
+                let new_addrs: Vec<SocketAddr> = addrs
+                    .into_iter()
+                    .filter(|addr| !self.peers.contains_key(addr))
+                    .take(TRACKER_MAX_PEERS)
+                    .collect();
+
+                for addr in new_addrs {
+                    self.search_for_peers(addr)?;
+                }
+            }
+            Dht(node_id, msg) => {
+                if node_id == self.node_id {
+                    return Ok(());
+                }
+
+                for addr in self.dht.handle_msg(from, node_id, msg)? {
+                    if !self.peers.contains_key(&addr) {
+                        self.search_for_peers(addr)?;
+                    }
+                }
+            }
+            // we're not a tracker
+            Announce(_) | FindBoxPeers(_) => (),
+            FindMetadata(hash) => {
+                self.peer_has_box(from, hash);
+                if !self.authorized(from, hash)? {
+                    return Ok(());
+                }
+
+                // older peers would read a merkle box as having no pieces at all, and
+                // couldn't check a chunk-hashed box's pieces
+                let required = self
+                    .boxes
+                    .get(&hash)
+                    .map(|b| b.required_features())
+                    .unwrap_or_default();
+                let understood = required == Features::default()
+                    || self
+                        .peers
+                        .get(&from)
+                        .map(|p| p.supports(required))
+                        .unwrap_or(false);
+
+                if let Some(metadata) = self.get_metadata(hash).filter(|_| understood) {
+                    self.send_packet(GotMetadata(hash, metadata).to_packet(from))?;
+                }
+            }
+            GotMetadata(hash, metadata) => {
+                self.peer_has_box(from, hash);
+                if let Some(desired) = self.looking_for_boxes.remove(&hash) {
+                    self.add_box(
+                        desired.path,
+                        hash,
+                        metadata,
+                        desired.strategy,
+                        desired.secret,
+                    )?;
+                    self.request_bitfields(hash)?;
+                }
+            }
+            FindPiece {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                self.peer_has_box(from, id);
+                if !self.authorized(from, id)? {
+                    return Ok(());
+                }
+
+                if let Some(file) = self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
+                    if file.has_piece(piece_index) {
+                        self.send_packet(
+                            GotPiece {
+                                id,
+                                file_index,
+                                piece_index,
+                            }
+                            .to_packet(from),
+                        )?;
+                    }
+                }
+            }
+            GotPiece {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                self.peer_has_box(from, id);
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    if peer
+                        .pieces
+                        .entry(id)
+                        .or_default()
+                        .insert((file_index, piece_index))
+                    {
+                        self.availability.add(id, (file_index, piece_index));
+                    }
+                }
+            }
+            GetBitfield(id) => {
+                self.peer_has_box(from, id);
+                if !self.authorized(from, id)? {
+                    return Ok(());
+                }
+
+                if let Some(b) = self.boxes.get(&id) {
+                    self.send_packet(Bitfield(id, b.bitfield()).to_packet(from))?;
+                }
+            }
+            Bitfield(id, files) => {
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    let pieces = peer.pieces.entry(id).or_default();
+                    for key in pieces.drain() {
+                        self.availability.remove(id, key);
+                    }
+
+                    for (file_index, bits) in files.iter().enumerate() {
+                        for (byte_index, byte) in bits.iter().enumerate() {
+                            for bit in 0..8 {
+                                if byte & (1 << bit) != 0 {
+                                    let key = (file_index, byte_index * 8 + bit);
+                                    pieces.insert(key);
+                                    self.availability.add(id, key);
+                                }
+                            }
+                        }
+                    }
+
+                    peer.boxes.insert(id);
+                }
+            }
+            StartDownload {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                if !self.authorized(from, id)? || !self.may_upload(from, id)? {
+                    return Ok(());
+                }
+
+                let key = (file_index, piece_index);
+                let chunk_count = self.upload_chunk_count(id, key);
+                let queued = self
+                    .uploads
+                    .iter()
+                    .any(|u| u.peer == from && u.id == id && u.key == key);
+
+                if chunk_count > 0 && !queued && self.uploads.len() < MAX_QUEUED_UPLOADS {
+                    self.uploads.push_back(PendingUpload {
+                        peer: from,
+                        id,
+                        key,
+                        chunks: (0..chunk_count).collect(),
+                    });
+                }
+            }
+            GetChunks {
+                id,
+                file_index,
+                piece_index,
+                chunks,
+            } => {
+                if !self.authorized(from, id)? || !self.may_upload(from, id)? {
+                    return Ok(());
+                }
+
+                let key = (file_index, piece_index);
+                let chunk_count = self.upload_chunk_count(id, key);
+                let chunks = chunks.into_iter().filter(|c| *c < chunk_count);
+                let full = self.uploads.len() >= MAX_QUEUED_UPLOADS;
+
+                // tack them onto the upload already going, if there is one
+                match self
+                    .uploads
+                    .iter_mut()
+                    .find(|u| u.peer == from && u.id == id && u.key == key)
+                {
+                    Some(upload) => {
+                        for chunk in chunks {
+                            if !upload.chunks.contains(&chunk) {
+                                upload.chunks.push_back(chunk);
+                            }
+                        }
+                    }
+                    None if !full => {
+                        let chunks: VecDeque<usize> = chunks.collect();
+                        if !chunks.is_empty() {
+                            self.uploads.push_back(PendingUpload {
+                                peer: from,
+                                id,
+                                key,
+                                chunks,
+                            });
+                        }
+                    }
+                    None => (),
+                }
+            }
+            Cancel {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                self.uploads.retain(|u| {
+                    !(u.peer == from && u.id == id && u.key == (file_index, piece_index))
+                });
+            }
+            Choke(id) => {
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    peer.choking_us.insert(id);
+                }
+
+                // whatever we asked them for isn't coming
+                for ((download_id, _), download) in self.downloads.iter_mut() {
+                    if *download_id == id {
+                        download.peers.retain(|p| *p != from);
+                    }
+                }
+                self.downloads.retain(|_, d| !d.peers.is_empty());
+            }
+            Unchoke(id) => {
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    peer.choking_us.remove(&id);
+                }
+            }
+            Challenge(id, nonce) => {
+                let challenger = self.peers.get(&from).and_then(|p| p.node_id);
+                if let (Some(secret), Some(challenger)) = (self.box_secret(&id), challenger) {
+                    let proof = prove(&secret, &id, &nonce, &challenger, &self.node_id);
+                    self.send_packet(ChallengeResponse(id, proof).to_packet(from))?;
+                }
+            }
+            ChallengeResponse(id, proof) => {
+                let secret = match self.boxes.get(&id).and_then(|b| b.secret) {
+                    Some(secret) => secret,
+                    None => return Ok(()),
+                };
+                let peer = match self.peers.get_mut(&from) {
+                    Some(peer) => peer,
+                    None => return Ok(()),
+                };
+                let (nonce, responder) = match (peer.challenges.remove(&id), peer.node_id) {
+                    (Some((nonce, _)), Some(responder)) => (nonce, responder),
+                    _ => return Ok(()),
+                };
+
+                if prove(&secret, &id, &nonce, &self.node_id, &responder) == proof {
+                    peer.authorized.insert(id);
+                    peer.bad_proofs.remove(&id);
+                } else {
+                    peer.bad_proofs.insert(id, Instant::now());
+                }
+            }
+            Upload {
+                id,
+                file_index,
+                piece_index,
+                chunk_index,
+                buf,
+            } => {
+                if let Some(peer) = self.peers.get_mut(&from) {
+                    *peer.uploaded_to_us.entry(id).or_default() += buf.len() as u64;
+                }
+
+                let result = match self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
+                    Some(file) if !file.has_piece(piece_index) => {
+                        file.write_chunk(piece_index, chunk_index, &buf)
+                    }
+                    _ => return Ok(()),
+                };
+                let key = (id, (file_index, piece_index));
+
+                match result {
+                    ChunkResult::Rejected => self.penalize_malformed(from),
+                    ChunkResult::Accepted => {
+                        self.contributors
+                            .entry(key)
+                            .or_default()
+                            .insert(chunk_index, from);
+                    }
+                    ChunkResult::Completed | ChunkResult::Corrupt => {
+                        self.contributors
+                            .entry(key)
+                            .or_default()
+                            .insert(chunk_index, from);
+                        self.piece_done(key, result == ChunkResult::Completed)?;
+                    }
+                    // we know exactly who sent it, so just that chunk gets asked for again
+                    ChunkResult::BadChunk => {
+                        self.penalize(from, CORRUPT_CHUNK_PENALTY);
+                        self.rerequest_chunks(key, vec![chunk_index], Some(from))?;
+                    }
+                }
+            }
+            GetChunkHashes {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                if !self.authorized(from, id)? {
+                    return Ok(());
+                }
+
+                if let Some(hashes) = self
+                    .boxes
+                    .get(&id)
+                    .and_then(|b| b.files.get(file_index))
+                    .and_then(|f| f.piece_chunk_hashes(piece_index))
+                {
+                    self.send_packet(
+                        ChunkHashes {
+                            id,
+                            file_index,
+                            piece_index,
+                            hashes,
+                        }
+                        .to_packet(from),
+                    )?;
+                }
+            }
+            ChunkHashes {
+                id,
+                file_index,
+                piece_index,
+                hashes,
+            } => {
+                let bad = match self.boxes.get(&id).and_then(|b| b.files.get(file_index)) {
+                    Some(file) => file.set_chunk_hashes(piece_index, hashes),
+                    None => return Ok(()),
+                };
+                let key = (id, (file_index, piece_index));
+
+                match bad {
+                    None => self.penalize_malformed(from),
+                    // chunks that came in before we could check them
+                    Some(bad) => {
+                        for chunk in bad {
+                            let sender = self
+                                .contributors
+                                .get_mut(&key)
+                                .and_then(|c| c.remove(&chunk));
+                            if let Some(sender) = sender {
+                                self.penalize(sender, CORRUPT_CHUNK_PENALTY);
+                            }
+                            self.rerequest_chunks(key, vec![chunk], sender)?;
+                        }
+                    }
+                }
+            }
+            GetProof {
+                id,
+                file_index,
+                piece_index,
+            } => {
+                if !self.authorized(from, id)? {
+                    return Ok(());
+                }
+
+                let key = (file_index, piece_index);
+                if let Some((hash, proof)) = self.boxes.get(&id).and_then(|b| b.piece_proof(key)) {
+                    self.send_packet(
+                        Proof {
+                            id,
+                            file_index,
+                            piece_index,
+                            hash,
+                            proof,
+                        }
+                        .to_packet(from),
+                    )?;
+                }
+            }
+            Proof {
+                id,
+                file_index,
+                piece_index,
+                hash,
+                proof,
+            } => {
+                let result = match self.boxes.get(&id) {
+                    Some(b) => b.add_proof((file_index, piece_index), hash, &proof),
+                    None => return Ok(()),
+                };
+
+                match result {
+                    None => self.penalize_malformed(from),
+                    Some(ChunkResult::Completed) => {
+                        self.piece_done((id, (file_index, piece_index)), true)?
+                    }
+                    Some(ChunkResult::Corrupt) => {
+                        self.piece_done((id, (file_index, piece_index)), false)?
+                    }
+                    // still downloading, so now we can check the rest of it chunk by chunk
+                    Some(ChunkResult::Accepted) => {
+                        self.request_chunk_hashes((id, (file_index, piece_index)), from)?
+                    }
+                    // a duplicate
+                    Some(_) => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn search_for_metadata(&self) -> io::Result<()> {
+        for k in self.looking_for_boxes.keys() {
+            // older peers can't even read a longer hash
+            let long = k.algorithm() != HashAlgorithm::Blake2s128;
+            for peer in self
+                .peers
+                .values()
+                .filter(|p| p.is_connected() && (!long || p.supports(Features::LONG_HASHES)))
+            {
+                self.send_packet(Message::FindMetadata(*k).to_packet(peer.addr))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // asks peers for the pieces we still need, a few at a time per box, in whichever order
+    // the box's piece picker wants them
+    pub fn search_for_pieces(&mut self) -> io::Result<()> {
+        let timed_out: Vec<(BoxHash, PieceKey)> = self
+            .downloads
+            .iter()
+            .filter(|(_, d)| d.started.elapsed() > DOWNLOAD_TIMEOUT)
+            .map(|(k, _)| *k)
+            .collect();
+        for key in timed_out {
+            self.cancel_download(key)?;
+        }
+
+        self.dedup_pieces()?;
+
+        let mut requests: Vec<(BoxHash, PieceKey, SocketAddr)> = Vec::new();
+        let mut probes: Vec<(BoxHash, PieceKey)> = Vec::new();
+
+        for b in self.boxes.values() {
+            let remaining = b.needed_piece_keys();
+            if !remaining.is_empty() && remaining.len() <= ENDGAME_PIECES {
+                // endgame: ask everyone who has the last few pieces, so one slow peer
+                // can't hold up the whole box
+                for key in remaining {
+                    let asked = self
+                        .downloads
+                        .get(&(b.hash, key))
+                        .map(|d| d.peers.as_slice())
+                        .unwrap_or_default();
+                    let mut holders = self
+                        .peers
+                        .values()
+                        .filter(|p| p.can_send(&b.hash, &key) && !asked.contains(&p.addr))
+                        .take(ENDGAME_MAX_PEERS.saturating_sub(asked.len()))
+                        .peekable();
+
+                    if holders.peek().is_none() && asked.is_empty() {
+                        probes.push((b.hash, key));
+                    }
+
+                    for peer in holders {
+                        requests.push((b.hash, key, peer.addr));
+                    }
+                }
+
+                continue;
+            }
+
+            let mut in_flight = self
+                .downloads
+                .keys()
+                .filter(|(id, _)| *id == b.hash)
+                .count();
+            if in_flight >= MAX_DOWNLOADS_PER_BOX {
+                continue;
+            }
+
+            let mut needed: Vec<PieceKey> = b
+                .needed_piece_keys()
+                .into_iter()
+                .filter(|k| !self.downloads.contains_key(&(b.hash, *k)))
+                .collect();
+
+            let nobody = HashMap::new();
+            let availability = self.availability.of(&b.hash).unwrap_or(&nobody);
+            b.order_needed(&mut needed, availability);
+
+            for key in needed {
+                if in_flight >= MAX_DOWNLOADS_PER_BOX {
+                    break;
+                }
+
+                match self.pick_peer(b.hash, key, &requests) {
+                    Some(peer) => {
+                        requests.push((b.hash, key, peer));
+                        in_flight += 1;
+                    }
+                    None if probes.len() < MAX_PIECE_PROBES => probes.push((b.hash, key)),
+                    None => (),
+                }
+            }
+        }
+
+        for (id, (file_index, piece_index), peer) in requests {
+            // merkle pieces can't be verified until we know their hash, so ask for the
+            // proof while the chunks are coming in
+            if self
+                .boxes
+                .get(&id)
+                .map(|b| b.piece_hash((file_index, piece_index)).is_none())
+                .unwrap_or(false)
+            {
+                self.send_packet(
+                    Message::GetProof {
+                        id,
+                        file_index,
+                        piece_index,
+                    }
+                    .to_packet(peer),
+                )?;
+            }
+
+            self.downloads
+                .entry((id, (file_index, piece_index)))
+                .or_insert_with(|| Download {
+                    peers: Vec::new(),
+                    started: Instant::now(),
+                })
+                .peers
+                .push(peer);
+
+            self.send_packet(
+                Message::StartDownload {
+                    id,
+                    file_index,
+                    piece_index,
+                }
+                .to_packet(peer),
+            )?;
+            self.request_chunk_hashes((id, (file_index, piece_index)), peer)?;
+        }
+
+        // peers without bitfields only tell us what they have when asked piece by piece
+        for (id, (file_index, piece_index)) in probes {
+            for peer in self
+                .peers
+                .values()
+                .filter(|p| p.is_connected() && !p.supports(Features::BITFIELD))
+            {
+                self.send_packet(
+                    Message::FindPiece {
+                        id,
+                        file_index,
+                        piece_index,
+                    }
+                    .to_packet(peer.addr),
+                )?;
+                std::thread::sleep(Duration::from_millis(10)); // don't flood opencomputers
+            }
+        }
+
+        Ok(())
+    }
+
+    // a piece finished, and either verified or didn't. either way any other peers still
+    // sending it are now sending duplicates
+    fn piece_done(&mut self, key: (BoxHash, PieceKey), verified: bool) -> io::Result<()> {
+        let contributors = self.contributors.remove(&key).unwrap_or_default();
+
+        if verified {
+            self.index_piece(key);
+
+            // now we know what the chunks should have been, and so who sent bad ones
+            if let Some(suspects) = self.suspects.remove(&key) {
+                let digests = self.chunk_digests(key);
+                let liars: HashSet<SocketAddr> = suspects
+                    .into_iter()
+                    .filter(|(_, chunk, sent)| digests.get(*chunk) != Some(sent))
+                    .map(|(peer, _, _)| peer)
+                    .collect();
+                for peer in liars {
+                    self.penalize(peer, CORRUPT_PIECE_PENALTY);
+                }
+            }
+        } else {
+            let senders: HashSet<SocketAddr> = contributors.values().copied().collect();
+            if senders.len() == 1 {
+                for peer in senders {
+                    self.penalize(peer, CORRUPT_PIECE_PENALTY);
+                }
+            } else {
+                // can't tell whose chunk was bad, so remember what everyone sent until
+                // the piece verifies, rather than blaming peers that did nothing wrong
+                let digests = self.chunk_digests(key);
+                let suspects = self.suspects.entry(key).or_default();
+                for (chunk, peer) in contributors {
+                    if let Some(sent) = digests.get(chunk) {
+                        suspects.push((peer, chunk, *sent));
+                    }
+                }
+            }
+        }
+
+        self.cancel_download(key)
+    }
+
+    fn chunk_digests(&self, key: (BoxHash, PieceKey)) -> Vec<PieceHash> {
+        let (id, (file_index, piece_index)) = key;
+
+        self.boxes
+            .get(&id)
+            .and_then(|b| b.files.get(file_index))
+            .and_then(|f| f.piece_chunk_digests(piece_index))
+            .unwrap_or_default()
+    }
+
+    // asks for a piece's chunk hashes if its box has them and we don't yet, so each chunk
+    // can be checked as it arrives
+    fn request_chunk_hashes(&self, key: (BoxHash, PieceKey), peer: SocketAddr) -> io::Result<()> {
+        let (id, (file_index, piece_index)) = key;
+
+        let wanted = self
+            .boxes
+            .get(&id)
+            .and_then(|b| b.files.get(file_index))
+            .map(|f| f.wants_chunk_hashes(piece_index))
+            .unwrap_or(false);
+        let understood = self
+            .peers
+            .get(&peer)
+            .map(|p| p.supports(Features::CHUNK_HASHES))
+            .unwrap_or(false);
+
+        if wanted && understood {
+            self.send_packet(
+                Message::GetChunkHashes {
+                    id,
+                    file_index,
+                    piece_index,
+                }
+                .to_packet(peer),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // asks for some chunks again after bad ones were dropped, from someone other than
+    // `avoid` if anyone else has the piece
+    fn rerequest_chunks(
+        &mut self,
+        key: (BoxHash, PieceKey),
+        chunks: Vec<usize>,
+        avoid: Option<SocketAddr>,
+    ) -> io::Result<()> {
+        let (id, piece_key) = key;
+        let (file_index, piece_index) = piece_key;
+
+        let candidates: Vec<SocketAddr> = self
+            .peers
+            .values()
+            .filter(|p| p.can_send(&id, &piece_key) && p.supports(Features::CHUNK_HASHES))
+            .map(|p| p.addr)
+            .collect();
+        let peer = match candidates
+            .iter()
+            .find(|p| Some(**p) != avoid)
+            .or_else(|| candidates.first())
+        {
+            Some(peer) => *peer,
+            None => return Ok(()),
+        };
+
+        if let Some(download) = self.downloads.get_mut(&key) {
+            if !download.peers.contains(&peer) {
+                download.peers.push(peer);
+            }
+        }
+
+        self.send_packet(
+            Message::GetChunks {
+                id,
+                file_index,
+                piece_index,
+                chunks,
+            }
+            .to_packet(peer),
+        )
+    }
+
+    // how many chunks there are to upload of a piece, or 0 if we can't upload it
+    fn upload_chunk_count(&self, id: BoxHash, (file_index, piece_index): PieceKey) -> usize {
+        self.boxes
+            .get(&id)
+            .and_then(|b| b.files.get(file_index))
+            .filter(|f| f.has_piece(piece_index))
+            .and_then(|f| {
+                f.pieces
+                    .get(piece_index)
+                    .map(|p| f.layout.chunk_count(p.size))
+            })
+            .unwrap_or(0)
+    }
+
+    // whether `from` gets to download `id` from us right now. peers that are choked but
+    // asked anyway are reminded of it
+    fn may_upload(&mut self, from: SocketAddr, id: BoxHash) -> io::Result<bool> {
+        if !self.boxes.contains_key(&id) {
+            return Ok(false);
+        }
+
+        let peer = match self.peers.get_mut(&from) {
+            Some(peer) => peer,
+            None => return Ok(false),
+        };
+        peer.interested.insert(id, Instant::now());
+
+        if peer.unchoked.contains(&id) {
+            return Ok(true);
+        }
+        let supports_choke = peer.supports(Features::CHOKE);
+
+        // there's a free slot, no need to wait for the next rechoke
+        let slots_used = self
+            .peers
+            .values()
+            .filter(|p| p.unchoked.contains(&id))
+            .count();
+        if slots_used < self.upload_slots {
+            self.set_choked(from, id, false)?;
+            return Ok(true);
+        }
+
+        if supports_choke {
+            self.send_packet(Message::Choke(id).to_packet(from))?;
+        }
+
+        Ok(false)
+    }
+
+    fn set_choked(&mut self, addr: SocketAddr, id: BoxHash, choked: bool) -> io::Result<()> {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        if choked {
+            peer.unchoked.remove(&id);
+        } else {
+            peer.unchoked.insert(id);
+        }
+        let supports_choke = peer.supports(Features::CHOKE);
+
+        if choked {
+            self.uploads.retain(|u| !(u.peer == addr && u.id == id));
+        }
+
+        if supports_choke {
+            let msg = if choked {
+                Message::Choke(id)
+            } else {
+                Message::Unchoke(id)
+            };
+            self.send_packet(msg.to_packet(addr))?;
+        }
+
+        Ok(())
+    }
+
+    // tit-for-tat: for each box, the peers that sent us the most since last time get our
+    // upload slots, except one which rotates between everyone else so new peers get a go
+    pub fn rechoke(&mut self) -> io::Result<()> {
+        let rotate = self.last_optimistic_unchoke.elapsed() > OPTIMISTIC_UNCHOKE_INTERVAL;
+        if rotate {
+            self.last_optimistic_unchoke = Instant::now();
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut changes: Vec<(SocketAddr, BoxHash, bool)> = Vec::new();
+
+        for id in self.boxes.keys() {
+            let mut interested: Vec<&Peer> = self
+                .peers
+                .values()
+                .filter(|p| p.is_connected() && p.is_interested(id, INTEREST_TIMEOUT))
+                .collect();
+            interested.shuffle(&mut rng);
+            interested.sort_by_key(|p| Reverse(p.uploaded_to_us.get(id).copied().unwrap_or(0)));
+
+            let mut unchoke: HashSet<SocketAddr> = interested
+                .iter()
+                .take(self.upload_slots.saturating_sub(1))
+                .map(|p| p.addr)
+                .collect();
+
+            let optimistic = match self.optimistic_unchokes.get(id) {
+                Some(addr) if !rotate && interested.iter().any(|p| p.addr == *addr) => Some(*addr),
+                _ => interested
+                    .iter()
+                    .filter(|p| !unchoke.contains(&p.addr))
+                    .map(|p| p.addr)
+                    .collect::<Vec<SocketAddr>>()
+                    .choose(&mut rng)
+                    .copied(),
+            };
+
+            match optimistic {
+                Some(addr) => {
+                    unchoke.insert(addr);
+                    self.optimistic_unchokes.insert(*id, addr);
+                }
+                None => {
+                    self.optimistic_unchokes.remove(id);
+                }
+            }
+
+            for peer in self.peers.values() {
+                let unchoked = unchoke.contains(&peer.addr);
+                if peer.unchoked.contains(id) != unchoked {
+                    changes.push((peer.addr, *id, unchoked));
+                }
+            }
+        }
+
+        for (addr, id, unchoked) in changes {
+            self.set_choked(addr, id, !unchoked)?;
+        }
+
+        for peer in self.peers.values_mut() {
+            peer.uploaded_to_us.clear();
+        }
+
+        Ok(())
+    }
+
+    // forgets about a download and tells whoever was sending it to stop
+    pub fn cancel_download(&mut self, key: (BoxHash, PieceKey)) -> io::Result<()> {
+        let (id, (file_index, piece_index)) = key;
+        self.contributors.remove(&key);
+
+        if let Some(download) = self.downloads.remove(&key) {
+            for peer in download.peers {
+                if self
+                    .peers
+                    .get(&peer)
+                    .map(|p| p.supports(Features::CANCEL))
+                    .unwrap_or(false)
+                {
+                    self.send_packet(
+                        Message::Cancel {
+                            id,
+                            file_index,
+                            piece_index,
+                        }
+                        .to_packet(peer),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel_all_downloads(&mut self) -> io::Result<()> {
+        for key in self.downloads.keys().copied().collect::<Vec<_>>() {
+            self.cancel_download(key)?;
+        }
+
+        Ok(())
+    }
+
+    // sends the next chunk of the upload queue, round-robin between uploads
+    pub fn process_uploads(&mut self) -> io::Result<()> {
+        if self.last_upload.elapsed() < UPLOAD_CHUNK_INTERVAL {
+            return Ok(());
+        }
+
+        let mut upload = match self.uploads.pop_front() {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+        let (file_index, piece_index) = upload.key;
+
+        // the box might have gone away since this was queued
+        let (data, chunk_size) = match self
+            .boxes
+            .get(&upload.id)
+            .and_then(|b| b.files.get(file_index))
+            .and_then(|f| f.read_piece(piece_index).map(|d| (d, f.layout.chunk_size)))
+        {
+            Some(piece) => piece,
+            None => return Ok(()),
+        };
+
+        let chunk_index = match upload.chunks.pop_front() {
+            Some(chunk_index) => chunk_index,
+            None => return Ok(()),
+        };
+        let buf = match data.chunks(chunk_size).nth(chunk_index) {
+            Some(chunk) => chunk.to_vec(),
+            None => return Ok(()),
+        };
+
+        let binary = self
+            .peers
+            .get(&upload.peer)
+            .map(|p| p.supports(Features::BINARY_UPLOADS))
+            .unwrap_or(false);
+        let packet = if binary {
+            Message::Upload {
+                id: upload.id,
+                file_index,
+                piece_index,
+                chunk_index,
+                buf,
+            }
+            .to_packet(upload.peer)
+        } else {
+            LegacyUpload::Upload {
+                id: upload.id,
+                file_index,
+                piece_index,
+                chunk_index,
+                buf,
+            }
+            .to_packet(upload.peer)
+        };
+        self.send_packet(packet)?;
+        self.last_upload = Instant::now();
+
+        if !upload.chunks.is_empty() {
+            self.uploads.push_back(upload);
+        }
+
+        Ok(())
+    }
+
+    // the least busy peer that has the piece, with ties going to whoever answers fastest
+    fn pick_peer(
+        &self,
+        id: BoxHash,
+        key: PieceKey,
+        pending: &[(BoxHash, PieceKey, SocketAddr)],
+    ) -> Option<SocketAddr> {
+        self.peers
+            .values()
+            .filter(|p| p.can_send(&id, &key))
+            .min_by_key(|p| {
+                let load = self
+                    .downloads
+                    .values()
+                    .filter(|d| d.peers.contains(&p.addr))
+                    .count()
+                    + pending
+                        .iter()
+                        .filter(|(_, _, addr)| *addr == p.addr)
+                        .count();
+                (load, p.rtt.unwrap_or(Duration::MAX))
+            })
+            .map(|p| p.addr)
+    }
+
+    pub fn request_bitfields(&self, id: BoxHash) -> io::Result<()> {
+        for peer in self
+            .peers
+            .values()
+            .filter(|p| p.is_connected() && p.supports(Features::BITFIELD))
+        {
+            self.send_packet(Message::GetBitfield(id).to_packet(peer.addr))?;
+        }
+
+        Ok(())
+    }
+
+    // refreshes what our peers have for every box we're still downloading
+    pub fn refresh_bitfields(&self) -> io::Result<()> {
+        for b in self.boxes.values() {
+            if !b.needed_pieces().is_empty() {
+                self.request_bitfields(b.hash)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // whether an address somebody told us about is worth a hello. they pick these, so
+    // don't let them aim us at ourselves, a whole network, or somewhere we can't reach
+    fn could_be_peer(&self, from: SocketAddr, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        let everyone = match ip {
+            IpAddr::V4(v4) => v4.is_broadcast(),
+            IpAddr::V6(_) => false,
+        };
+
+        addr.is_ipv4() == from.is_ipv4()
+            && addr.port() != 0
+            && !ip.is_unspecified()
+            && !ip.is_multicast()
+            && !everyone
+            && !self.own_addrs.contains(addr)
+            && self.acl.permits(&ip)
+    }
+
+    pub fn search_for_peers(&self, addr: SocketAddr) -> io::Result<()> {
+        self.send_plain(LaminarPacket::unreliable(
+            addr,
+            rmp_serde::to_vec(&Message::SearchingForPeers(Hello::new(self.node_id))).unwrap(),
+        ))
+    }
+
+    // asks every peer for some of the peers they know, both in general and for the boxes
+    // we're after, so we can find swarm members broadcast can't reach
+    pub fn exchange_peers(&mut self) -> io::Result<()> {
+        let wanted: Vec<BoxHash> = self
+            .looking_for_boxes
+            .keys()
+            .chain(
+                self.boxes
+                    .values()
+                    .filter(|b| !b.needed_pieces().is_empty())
+                    .map(|b| &b.hash),
+            )
+            .copied()
+            .collect();
+
+        let mut requests: Vec<(SocketAddr, Option<BoxHash>)> = Vec::new();
+        for peer in self
+            .peers
+            .values_mut()
+            .filter(|p| p.is_connected() && p.supports(Features::PEX))
+        {
+            peer.pex_pending.insert(None);
+            requests.push((peer.addr, None));
+
+            for hash in wanted.iter().filter(|h| peer.boxes.contains(h)) {
+                peer.pex_pending.insert(Some(*hash));
+                requests.push((peer.addr, Some(*hash)));
+            }
+        }
+
+        for (addr, hash) in requests {
+            self.send_packet(Message::GetPeers(hash).to_packet(addr))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn announce_to_trackers(&self) -> io::Result<()> {
+        if self.trackers.is_empty() {
+            return Ok(());
+        }
+
+        let held: Vec<BoxHash> = self.boxes.keys().copied().collect();
+        // trackers only answer addresses that have announced, so say hello with nothing
+        // rather than staying quiet
+        if held.is_empty() {
+            for tracker in &self.trackers {
+                self.send_plain(Message::Announce(Vec::new()).to_packet(*tracker))?;
+            }
+        }
+        for chunk in held.chunks(TRACKER_MAX_ANNOUNCE) {
+            for tracker in &self.trackers {
+                self.send_plain(Message::Announce(chunk.to_vec()).to_packet(*tracker))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn query_trackers(&self) -> io::Result<()> {
+        let wanted = self.looking_for_boxes.keys().chain(
+            self.boxes
+                .values()
+                .filter(|b| !b.needed_pieces().is_empty())
+                .map(|b| &b.hash),
+        );
+
+        for hash in wanted {
+            for tracker in &self.trackers {
+                self.send_plain(Message::FindBoxPeers(*hash).to_packet(*tracker))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // keeps our routing table fresh, looks up peers for boxes we want and announces
+    // the ones we hold
+    pub fn search_dht(&mut self) -> io::Result<()> {
+        self.dht.find_node(self.node_id)?;
+
+        for hash in self.looking_for_boxes.keys() {
+            self.dht.find_providers(*hash, false)?;
+        }
+
+        for hash in self.boxes.keys() {
+            self.dht.find_providers(*hash, true)?;
+        }
+
+        self.flush_dht()
+    }
+
+    pub fn tick_dht(&mut self) -> io::Result<()> {
+        self.dht.tick()?;
+        self.flush_dht()
+    }
+
+    fn flush_dht(&self) -> io::Result<()> {
+        for packet in self.dht_outbox.try_iter() {
+            self.send_packet(packet)?;
+        }
+
+        Ok(())
+    }
+
+    // unicast discovery for peers that broadcast can't reach
+    pub fn contact_static_peers(&mut self) -> io::Result<()> {
+        for peer in self.static_peers.clone() {
+            self.ping_peer(peer)?;
+        }
+
+        Ok(())
+    }
+
+    // re-asks every known peer, so we keep last_seen/rtt fresh
+    pub fn ping_peers(&mut self) -> io::Result<()> {
+        for peer in self.peers.keys().copied().collect::<Vec<SocketAddr>>() {
+            self.ping_peer(peer)?;
+        }
+
+        Ok(())
+    }
+
+    fn ping_peer(&mut self, addr: SocketAddr) -> io::Result<()> {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.pinged();
+        }
+
+        self.search_for_peers(addr)
+    }
+
+    // seals the packet if we have a session with its destination. without one, it's
+    // dropped if we require encryption, and it'll go again once we have a session
+    fn send_packet(&self, packet: LaminarPacket) -> io::Result<()> {
+        let session = match self.sessions.get(&packet.addr()) {
+            Some(session) => session,
+            None if self.require_encryption => return self.hold_until_sealed(packet),
+            None => return self.send_plain(packet),
+        };
+
+        let (counter, ciphertext) = session.seal(packet.payload());
+        let payload = rmp_serde::to_vec(&Message::Sealed(counter, ciphertext)).unwrap();
+        self.send_plain(match packet.delivery_guarantee() {
+            DeliveryGuarantee::Unreliable => LaminarPacket::unreliable(packet.addr(), payload),
+            _ => LaminarPacket::reliable_unordered(packet.addr(), payload),
+        })
+    }
+
+    // without a session nothing can go out yet, so keep it and say hello, which gets
+    // one of us to start a handshake
+    fn hold_until_sealed(&self, packet: LaminarPacket) -> io::Result<()> {
+        let addr = packet.addr();
+        if let Some(peer) = self.peers.get(&addr) {
+            if !peer.supports(Features::ENCRYPTION) {
+                return Ok(());
+            }
+        }
+
+        let first = {
+            let mut unsent = self.unsent.borrow_mut();
+            let (_, held) = unsent
+                .entry(addr)
+                .or_insert_with(|| (Instant::now(), Vec::new()));
+            if held.len() < MAX_HELD_PACKETS {
+                held.push(packet);
+            }
+            held.len() == 1
+        };
+
+        if first {
+            self.search_for_peers(addr)?;
+        }
+        Ok(())
+    }
+
+    fn send_plain(&self, packet: LaminarPacket) -> io::Result<()> {
+        // however we heard of a denied address, we don't talk to it. multicast groups
+        // aren't peers, so discovery still works with an allow list
+        let ip = packet.addr().ip();
+        if !ip.is_multicast() && !self.acl.permits(&ip) {
+            return Ok(());
+        }
+
+        self.packet_sender.send(packet).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a state with one peer that's said hello, and the packets it sends
+    fn state_with_peer(peer: SocketAddr) -> (NyahState, Receiver<LaminarPacket>) {
+        let (sender, packets) = crossbeam_channel::unbounded();
+        let mut state = NyahState::new(sender, Identity::generate(), 1);
+        state
+            .handle_msg(peer, Message::SearchingForPeers(Hello::new([1; 16])))
+            .unwrap();
+        assert!(state.peers.contains_key(&peer));
+
+        (state, packets)
+    }
+
+    #[test]
+    fn unauthenticated_garbage_cant_ban() {
+        let peer: SocketAddr = "10.0.0.1:25565".parse().unwrap();
+        let (mut state, _packets) = state_with_peer(peer);
+
+        let bad_handshake = Message::Handshake {
+            static_key: [7; 32],
+            ephemeral: [7; 32],
+        };
+        for _ in 0..BAN_THRESHOLD {
+            // 0xc1 is never valid msgpack
+            let garbage = LaminarPacket::unreliable(peer, vec![0xc1]);
+            state.handle_packet(SocketEvent::Packet(garbage)).unwrap();
+            state
+                .handle_packet(SocketEvent::Packet(bad_handshake.to_packet(peer)))
+                .unwrap();
+        }
+
+        assert_eq!(state.reputation.penalty(&peer), 0);
+        assert!(!state.reputation.is_banned(&peer));
+        assert!(state.peers.contains_key(&peer));
+    }
+
+    fn sent(packets: &Receiver<LaminarPacket>) -> Vec<(SocketAddr, Message)> {
+        packets
+            .try_iter()
+            .map(|p| (p.addr(), Message::decode(p.payload()).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn answers_pex_once_per_interval() {
+        let peer: SocketAddr = "10.0.0.1:25565".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:25565".parse().unwrap();
+        let (mut state, packets) = state_with_peer(peer);
+        state
+            .handle_msg(other, Message::SearchingForPeers(Hello::new([2; 16])))
+            .unwrap();
+        sent(&packets);
+
+        for _ in 0..3 {
+            state.handle_msg(peer, Message::GetPeers(None)).unwrap();
+        }
+
+        let answers: Vec<Vec<SocketAddr>> = sent(&packets)
+            .into_iter()
+            .filter_map(|(to, msg)| match msg {
+                Message::KnownPeers(None, addrs) if to == peer => Some(addrs),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(answers, vec![vec![other]]);
+
+        // but after PEX_INTERVAL they can ask again
+        let long_ago = Instant::now().checked_sub(PEX_INTERVAL).unwrap();
+        state
+            .peers
+            .get_mut(&peer)
+            .unwrap()
+            .last_pex_request
+            .insert(None, long_ago);
+        state.handle_msg(peer, Message::GetPeers(None)).unwrap();
+        assert!(sent(&packets)
+            .iter()
+            .any(|(to, msg)| *to == peer && matches!(msg, Message::KnownPeers(..))));
+    }
+
+    #[test]
+    fn only_takes_pex_answers_it_asked_for() {
+        let peer: SocketAddr = "10.0.0.1:25565".parse().unwrap();
+        let (mut state, packets) = state_with_peer(peer);
+        let ours: SocketAddr = "10.0.0.9:25565".parse().unwrap();
+        state.own_addrs.insert(ours);
+        sent(&packets);
+
+        let stranger: SocketAddr = "10.0.0.3:25565".parse().unwrap();
+        let offered = vec![
+            stranger,
+            ours,
+            "224.0.0.1:25565".parse().unwrap(),
+            "255.255.255.255:25565".parse().unwrap(),
+            "0.0.0.0:25565".parse().unwrap(),
+            "10.0.0.4:0".parse().unwrap(),
+            "[fd00::1]:25565".parse().unwrap(),
+        ];
+        let hellos = |packets: &Receiver<LaminarPacket>| -> Vec<SocketAddr> {
+            sent(packets)
+                .into_iter()
+                .filter(|(_, msg)| matches!(msg, Message::SearchingForPeers(_)))
+                .map(|(to, _)| to)
+                .collect()
+        };
+
+        // nobody asked
+        state
+            .handle_msg(peer, Message::KnownPeers(None, offered.clone()))
+            .unwrap();
+        assert!(hellos(&packets).is_empty());
+
+        state.exchange_peers().unwrap();
+        sent(&packets);
+        state
+            .handle_msg(peer, Message::KnownPeers(None, offered.clone()))
+            .unwrap();
+        assert_eq!(hellos(&packets), vec![stranger]);
+
+        // one answer per question
+        state
+            .handle_msg(peer, Message::KnownPeers(None, offered))
+            .unwrap();
+        assert!(hellos(&packets).is_empty());
     }
 }
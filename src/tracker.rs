@@ -0,0 +1,148 @@
+use crate::*;
+
+use crossbeam_channel::Sender;
+use laminar::{Packet as LaminarPacket, SocketEvent};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_TRACKER_TTL: Duration = Duration::from_secs(5 * 60);
+pub const TRACKER_MAX_PEERS: usize = 50;
+pub const TRACKER_MAX_ANNOUNCE: usize = 256; // boxes per announce
+
+// keeps track of who holds which boxes, for nodes that broadcast can't reach
+pub struct Tracker {
+    pub swarms: HashMap<BoxHash, HashMap<SocketAddr, Instant>>,
+    // who's announced lately. only they get answers, so a spoofed FindBoxPeers can't
+    // point our replies at somebody who never talked to us
+    pub announcers: HashMap<SocketAddr, Instant>,
+    pub ttl: Duration,
+    packet_sender: Sender<LaminarPacket>,
+}
+
+impl Tracker {
+    pub fn new(sender: Sender<LaminarPacket>) -> Tracker {
+        Tracker {
+            swarms: HashMap::new(),
+            announcers: HashMap::new(),
+            ttl: DEFAULT_TRACKER_TTL,
+            packet_sender: sender,
+        }
+    }
+
+    pub fn handle_packet(&mut self, event: SocketEvent) -> io::Result<()> {
+        if let SocketEvent::Packet(p) = event {
+            if let Ok(msg) = rmp_serde::from_slice(p.payload()) {
+                self.handle_msg(p.addr(), msg)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_msg(&mut self, from: SocketAddr, message: Message) -> io::Result<()> {
+        use Message::*;
+
+        match message {
+            Announce(boxes) => {
+                self.announcers.insert(from, Instant::now());
+                for hash in boxes.into_iter().take(TRACKER_MAX_ANNOUNCE) {
+                    self.swarms
+                        .entry(hash)
+                        .or_default()
+                        .insert(from, Instant::now());
+                }
+            }
+            FindBoxPeers(hash) => {
+                if !self.announcers.contains_key(&from) {
+                    return Ok(());
+                }
+
+                let mut peers: Vec<(&SocketAddr, &Instant)> = self
+                    .swarms
+                    .get(&hash)
+                    .map(|swarm| swarm.iter().filter(|(addr, _)| **addr != from).collect())
+                    .unwrap_or_default();
+                peers.sort_by_key(|(_, announced)| announced.elapsed());
+
+                let addrs = peers
+                    .into_iter()
+                    .take(TRACKER_MAX_PEERS)
+                    .map(|(addr, _)| *addr)
+                    .collect();
+                self.send_packet(BoxPeers(hash, addrs).to_packet(from))?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    pub fn expire(&mut self) {
+        let ttl = self.ttl;
+        for swarm in self.swarms.values_mut() {
+            swarm.retain(|_, announced| announced.elapsed() < ttl);
+        }
+
+        self.swarms.retain(|_, swarm| !swarm.is_empty());
+        self.announcers
+            .retain(|_, announced| announced.elapsed() < ttl);
+    }
+
+    fn send_packet(&self, packet: LaminarPacket) -> io::Result<()> {
+        self.packet_sender.send(packet).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn finds_announced_peers() {
+        let (sender, packets) = crossbeam_channel::unbounded();
+        let mut tracker = Tracker::new(sender);
+        let hash = BoxHash::from([1; 16]);
+        let (a, b, asker) = (
+            addr("127.0.0.1:5001"),
+            addr("127.0.0.1:5002"),
+            addr("127.0.0.1:5003"),
+        );
+
+        tracker
+            .handle_msg(a, Message::Announce(vec![hash]))
+            .unwrap();
+        tracker
+            .handle_msg(b, Message::Announce(vec![hash]))
+            .unwrap();
+
+        // nothing for an address that never announced
+        tracker
+            .handle_msg(asker, Message::FindBoxPeers(hash))
+            .unwrap();
+        assert!(packets.try_recv().is_err());
+
+        tracker
+            .handle_msg(asker, Message::Announce(Vec::new()))
+            .unwrap();
+        tracker
+            .handle_msg(asker, Message::FindBoxPeers(hash))
+            .unwrap();
+        let reply = packets.try_recv().unwrap();
+        assert_eq!(reply.addr(), asker);
+        match Message::decode(reply.payload()).unwrap() {
+            Message::BoxPeers(found, mut addrs) => {
+                assert_eq!(found, hash);
+                addrs.sort();
+                assert_eq!(addrs, vec![a, b]);
+            }
+            other => panic!("expected BoxPeers, got {:?}", other),
+        }
+    }
+}
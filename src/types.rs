@@ -1,14 +1,146 @@
+use crate::acl::{Acl, AclKind, Cidr};
+use crate::channel::{ChannelId, SignedRevision};
+use crate::dht::DhtKey;
+use crate::hash::HashAlgorithm;
+use crate::hashing::CreationId;
+use crate::picker::PickStrategy;
+use crate::secret::{Nonce, Proof};
 use crate::*;
 
 use laminar::Packet as LaminarPacket;
-use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::BitOr;
 use std::path::PathBuf;
 
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// optional protocol extensions. peers only get sent messages for features they announced
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    pub const PEX: Features = Features(1 << 0);
+    pub const DHT: Features = Features(1 << 1);
+    pub const BITFIELD: Features = Features(1 << 2);
+    pub const CANCEL: Features = Features(1 << 3);
+    pub const CHOKE: Features = Features(1 << 4);
+    pub const PRIVATE: Features = Features(1 << 5);
+    pub const ENCRYPTION: Features = Features(1 << 6);
+    pub const CHANNELS: Features = Features(1 << 7);
+    pub const MERKLE: Features = Features(1 << 8);
+    pub const CHUNK_HASHES: Features = Features(1 << 9);
+    pub const LAYOUT: Features = Features(1 << 10); // piece and chunk sizes besides the defaults
+    pub const LONG_HASHES: Features = Features(1 << 11); // anything but 16 byte hashes
+    pub const BINARY_UPLOADS: Features = Features(1 << 12); // chunks as bytes, not number arrays
+
+    pub fn supported() -> Features {
+        Features::PEX
+            | Features::DHT
+            | Features::BITFIELD
+            | Features::CANCEL
+            | Features::CHOKE
+            | Features::PRIVATE
+            | Features::ENCRYPTION
+            | Features::CHANNELS
+            | Features::MERKLE
+            | Features::CHUNK_HASHES
+            | Features::LAYOUT
+            | Features::LONG_HASHES
+            | Features::BINARY_UPLOADS
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    pub node_id: NodeId,
+    #[serde(default)]
+    pub features: Features,
+}
+
+impl Hello {
+    pub fn new(node_id: NodeId) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            node_id,
+            features: Features::supported(),
+        }
+    }
+
+    // what a bare SearchingForPeers or ImHere from an older node amounts to. they don't
+    // send a node id, so version 0 means there isn't one
+    pub fn legacy() -> Hello {
+        Hello {
+            version: 0,
+            node_id: NodeId::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+// SearchingForPeers and ImHere as they were before hellos, which opencomputers nodes
+// still send and expect
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum LegacyHello {
+    SearchingForPeers,
+    ImHere,
+}
+
+impl IPCResponse {
+    pub fn failed(e: &io::Error) -> IPCResponse {
+        IPCResponse::Failed(format!("{:?}: {}", e.kind(), e))
+    }
+}
+
+impl LegacyHello {
+    pub fn to_packet(self, dest: SocketAddr) -> LaminarPacket {
+        LaminarPacket::reliable_unordered(dest, rmp_serde::to_vec(&self).unwrap())
+    }
+}
+
+// Upload with its chunk as an array of numbers rather than binary, for peers from before
+// Features::BINARY_UPLOADS. we read either kind
+#[derive(Clone, Debug, Serialize)]
+pub enum LegacyUpload {
+    Upload {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+        chunk_index: usize,
+        buf: Vec<u8>,
+    },
+}
+
+impl LegacyUpload {
+    pub fn to_packet(&self, dest: SocketAddr) -> LaminarPacket {
+        LaminarPacket::reliable_unordered(dest, rmp_serde::to_vec(self).unwrap())
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BoxState {
     pub name: String,
-    pub box_hash: BoxHash,
+    pub box_hash: Option<BoxHash>, // None until a new box is done hashing
+    pub hashing: Option<(usize, usize)>, // pieces hashed and total, while we hash files
+    pub strategy: PickStrategy,
+    pub private: bool,
+    pub bytes_saved: u64,
     pub files: Vec<FileState>,
 }
 
@@ -17,31 +149,106 @@ pub struct FileState {
     pub path: PathBuf,
     pub pieces_downloaded: usize,
     pub total_pieces: usize,
+    pub size: usize,
+    pub contiguous_bytes: usize, // verified bytes from the start, safe to stream
+    pub sequential: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BoxOptions {
+    pub passphrase: Option<String>, // makes the box private
+    pub merkle: bool,               // a merkle root per file instead of every piece hash
+    pub chunk_hashes: bool,         // lets downloaders check each chunk as it arrives
+    pub piece_size: Option<usize>,  // PIECE_SIZE if unset
+    pub chunk_size: Option<usize>,  // CHUNK_SIZE if unset
+    pub hash_algorithm: HashAlgorithm,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelInfo {
+    pub id: ChannelId,
+    pub seq: Option<u64>,
+    pub box_hash: Option<BoxHash>,
+    pub publishing: bool,
+    pub subscribed: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ConnectionState {
+    Connected,
+    TimedOut,
+    Disconnected,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub last_seen_secs: u64,
+    pub rtt_ms: Option<u64>,
+    pub state: ConnectionState,
+    pub version: u32,
+    pub features: Features,
+    pub penalty: u32,
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BanInfo {
+    pub addr: SocketAddr,
+    pub banned_secs: u64,
+    pub penalty: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum IPCResponse {
     Ok,
     NotFound,
-    BoxCreated(BoxHash),
-    Peers(Vec<SocketAddr>),
+    Denied,               // not allowed, like a peer the acl turns away
+    Creating(CreationId), // hashing in the background, see GetCreation
+    Failed(String),       // the call was fine, but doing it wasn't
+    Peers(Vec<PeerInfo>),
+    Bans(Vec<BanInfo>),
+    Acl(Acl),
+    Channels(Vec<ChannelInfo>),
     Box(BoxState),
     Boxes(Vec<BoxState>),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum IPCCall {
-    CreateBox(String, PathBuf),
-    DownloadBox(BoxHash, PathBuf),
+    CreateBox(String, PathBuf, BoxOptions),
+    GetCreation(CreationId),
+    DownloadBox(BoxHash, PathBuf, PickStrategy, Option<String>),
+    SetPickStrategy(BoxHash, PickStrategy),
+    SetSequential(BoxHash, PathBuf, bool),
     GetBoxState(BoxHash),
     GetAllPeers,
     GetAllBoxes,
+    RemoveBox(BoxHash),
+    Shutdown,
+    AddPeer(SocketAddr),
+    RemovePeer(SocketAddr),
+    GetBans,
+    ClearBans(Option<IpAddr>), // None clears every ban
+    GetAcl,
+    AddAclRule(AclKind, Cidr),
+    RemoveAclRule(AclKind, Cidr),
+    Publish(PathBuf, String, PathBuf), // key file, box name, box path
+    Subscribe(ChannelId, PathBuf, PickStrategy),
+    Unsubscribe(ChannelId),
+    GetChannels,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Message {
-    SearchingForPeers,
-    ImHere,
+    SearchingForPeers(Hello),
+    ImHere(Hello),
+    GetPeers(Option<BoxHash>),
+    KnownPeers(Option<BoxHash>, Vec<SocketAddr>),
+    Announce(Vec<BoxHash>),
+    FindBoxPeers(BoxHash),
+    BoxPeers(BoxHash, Vec<SocketAddr>),
+    Dht(NodeId, DhtMessage),
     FindMetadata(BoxHash),
     GotMetadata(BoxHash, CardboardMetadata),
     FindPiece {
@@ -54,6 +261,8 @@ pub enum Message {
         file_index: usize,
         piece_index: usize,
     },
+    GetBitfield(BoxHash),
+    Bitfield(BoxHash, Vec<Vec<u8>>), // one bitfield per file
     StartDownload {
         id: BoxHash,
         file_index: usize,
@@ -64,25 +273,154 @@ pub enum Message {
         file_index: usize,
         piece_index: usize,
         chunk_index: usize,
+        #[serde(with = "serde_bytes")]
         buf: Vec<u8>,
     },
+    Cancel {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+    },
+    Choke(BoxHash),
+    Unchoke(BoxHash),
+    // private boxes: prove you know the box's secret before we'll talk about it
+    Challenge(BoxHash, Nonce),
+    ChallengeResponse(BoxHash, Proof),
+    // sent in the clear to set up a session, everything after goes in Sealed
+    Handshake {
+        static_key: [u8; 32],
+        ephemeral: [u8; 32],
+    },
+    HandshakeReply {
+        static_key: [u8; 32],
+        ephemeral: [u8; 32],
+    },
+    Sealed(u64, #[serde(with = "serde_bytes")] Vec<u8>), // counter, encrypted message
+    FindChannel(ChannelId, u64),                         // anything newer than this seq?
+    ChannelUpdate(ChannelId, SignedRevision),
+    GetProof {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+    },
+    Proof {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+        hash: PieceHash,
+        proof: Vec<PieceHash>,
+    },
+    GetChunkHashes {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+    },
+    ChunkHashes {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+        hashes: Vec<PieceHash>,
+    },
+    GetChunks {
+        id: BoxHash,
+        file_index: usize,
+        piece_index: usize,
+        chunks: Vec<usize>, // just these, rather than the whole piece
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Contact {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DhtMessage {
+    FindNode(NodeId),
+    Nodes(NodeId, Vec<Contact>),
+    GetProviders(DhtKey),
+    Providers(DhtKey, Vec<SocketAddr>, Vec<Contact>),
+    AddProvider(DhtKey),
 }
 
 impl Message {
     pub fn to_packet(&self, dest: SocketAddr) -> LaminarPacket {
         LaminarPacket::reliable_unordered(dest, rmp_serde::to_vec(self).unwrap())
     }
+
+    pub fn decode(buf: &[u8]) -> Result<Message, rmp_serde::decode::Error> {
+        rmp_serde::from_slice(buf).or_else(|err| match rmp_serde::from_slice(buf) {
+            Ok(LegacyHello::SearchingForPeers) => Ok(Message::SearchingForPeers(Hello::legacy())),
+            Ok(LegacyHello::ImHere) => Ok(Message::ImHere(Hello::legacy())),
+            Err(_) => Err(err),
+        })
+    }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct CardboardMetadata {
     pub name: String,
     pub files: Vec<FileMetadata>,
+    #[serde(default)]
+    pub chunk_hashes: bool, // piece hashes are merkle roots over per-chunk hashes
+    #[serde(default = "default_piece_size")]
+    pub piece_size: usize,
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm, // for the box, file and piece hashes alike
+}
+
+// fields left at their defaults aren't sent, so a plain box looks (and hashes) the same as
+// it did to older peers. structs go out as arrays, so only a run of them at the end can be
+// left off, which is why this isn't skip_serializing_if
+impl Serialize for CardboardMetadata {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let extra = [
+            self.chunk_hashes,
+            self.piece_size != PIECE_SIZE,
+            self.chunk_size != CHUNK_SIZE,
+            self.hash_algorithm != HashAlgorithm::default(),
+        ]
+        .iter()
+        .rposition(|set| *set)
+        .map(|last| last + 1)
+        .unwrap_or(0);
+
+        let mut state = serializer.serialize_struct("CardboardMetadata", 2 + extra)?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("files", &self.files)?;
+        if extra > 0 {
+            state.serialize_field("chunk_hashes", &self.chunk_hashes)?;
+        }
+        if extra > 1 {
+            state.serialize_field("piece_size", &self.piece_size)?;
+        }
+        if extra > 2 {
+            state.serialize_field("chunk_size", &self.chunk_size)?;
+        }
+        if extra > 3 {
+            state.serialize_field("hash_algorithm", &self.hash_algorithm)?;
+        }
+        state.end()
+    }
+}
+
+fn default_piece_size() -> usize {
+    PIECE_SIZE
+}
+
+fn default_chunk_size() -> usize {
+    CHUNK_SIZE
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FileMetadata {
     pub path: PathBuf,
     pub size: usize,
-    pub pieces: Vec<[u8; 16]>,
+    pub pieces: Vec<PieceHash>, // empty if there's a merkle root
+    // left off when there isn't one, so older peers still get the three fields they expect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<PieceHash>,
 }